@@ -16,3 +16,7 @@ wget https://cdn.pyke.io/0/pyke:ort-rs/example-models@0.0.0/all-MiniLM-L6-v2.onn
 # and then use a python script to export
 # BERT
 optimum-cli export onnx --model distilbert/distilbert-base-cased-distilled-squad onnx_distilbert_qa/ --task question-answering
+# cross-encoder reranker
+optimum-cli export onnx --model cross-encoder/ms-marco-MiniLM-L-6-v2 onnx_reranker/ --task text-classification
+# local decoder for `synthesize local`, any causal LM exported with past key values works
+optimum-cli export onnx --model distilgpt2 onnx_decoder/ --task text-generation-with-past
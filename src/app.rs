@@ -1,138 +1,1289 @@
 use crate::models;
 use crate::utils;
 use crate::parser;
+use crate::grep;
+use crate::logs;
+use crate::prompt::{self, PromptTemplate};
+use crate::index::{self, IndexKind, VectorIndex};
+use crate::document::{self, Document, Filter, MetaValue, Metadata};
+use crate::bm25::{self, Bm25Index, Fusion};
 use models::ModelI;
-use ndarray::{Axis};
+use ndarray::{Array1, Array2, Axis};
+use serde::{Deserialize, Serialize};
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::Path;
 
 use ort::{
     Error as OrtError
 };
 
+// bump whenever the layout of VecDBFile changes
+pub const VECDB_FORMAT_VERSION: u32 = 6;
+
+// where the chunks of a VecDB came from, kept so a loaded index can be traced back
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceInfo {
+    pub location: String,
+    pub chunk_size: usize,
+    pub overlap: usize,
+    // id of the embedding model, vectors from different models are not comparable
+    pub model: String,
+    // models.json entry the model was read from, None for the default embedding model
+    #[serde(default)]
+    pub model_key: Option<String>,
+}
+
+// a piece of a document, row i of the embedding matrix belongs to chunk i.
+// metadata is inherited from the document, with line ranges narrowed to the chunk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chunk {
+    pub doc_id: String,
+    pub text: String,
+    // byte offset of `text` within the document
+    pub offset: usize,
+    pub metadata: Metadata,
+}
+
+// on-disk layout of a VecDB, embeddings are stored row-major
+#[derive(Serialize, Deserialize)]
+struct VecDBFile {
+    version: u32,
+    source: SourceInfo,
+    // the graph itself is rebuilt from the embeddings on load
+    index: IndexKind,
+    chunks: Vec<Chunk>,
+    dim: usize,
+    embeddings: Vec<f32>,
+}
+
+// which scorer(s) VecDB::search ranks chunks with
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum RetrievalMode {
+    #[default]
+    Semantic,
+    Lexical,
+    Hybrid(Fusion),
+}
+
+// picks results by maximal marginal relevance among the best `candidates`, see index::mmr_select
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MmrConfig {
+    pub lambda: f32,
+    pub candidates: usize,
+}
+
+impl Default for MmrConfig {
+    fn default() -> Self {
+        Self {
+            lambda: 0.5,
+            candidates: 20,
+        }
+    }
+}
+
+// everything that shapes a VecDB::search besides the query itself
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+    pub filters: Vec<Filter>,
+    pub mode: RetrievalMode,
+    pub mmr: Option<MmrConfig>,
+    // merge overlapping windows of the same document into one result
+    pub collapse_overlaps: bool,
+}
+
+impl RetrievalMode {
+    // "semantic", "lexical", "hybrid", "hybrid rrf 60" or "hybrid weighted 0.7"
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let words: Vec<&str> = spec.split_whitespace().collect();
+        let number = |i: usize, default: f32| -> Result<f32, String> {
+            words.get(i).map_or(Ok(default), |w| w.parse().map_err(|_| format!("'{}' is not a number", w)))
+        };
+        match words.as_slice() {
+            ["semantic"] => Ok(RetrievalMode::Semantic),
+            ["lexical"] => Ok(RetrievalMode::Lexical),
+            ["hybrid"] => Ok(RetrievalMode::Hybrid(Fusion::default())),
+            ["hybrid", "rrf", ..] => Ok(RetrievalMode::Hybrid(Fusion::Rrf { k: number(2, 60.0)? })),
+            ["hybrid", "weighted", ..] => match number(2, 0.5)? {
+                // the lexical side gets 1 - weight, outside [0, 1] one ranking is inverted
+                weight if (0.0..=1.0).contains(&weight) => Ok(RetrievalMode::Hybrid(Fusion::Weighted { semantic_weight: weight })),
+                weight => Err(format!("semantic weight must be between 0 and 1, got {}", weight)),
+            },
+            _ => Err(format!("unknown retrieval mode '{}'", spec)),
+        }
+    }
+}
+
 pub struct VecDB {
     embedding_model: models::EmbeddingModel,
-    chunks: Vec<String>
+    chunks: Vec<Chunk>,
+    source: SourceInfo,
+    index: Box<dyn VectorIndex>,
+    // lexical side of hybrid search, never persisted since it is cheap to rebuild
+    bm25: Bm25Index,
 }
 
 impl VecDB {
-    pub fn new(data: &str, chunk_size: usize, chunks: usize) -> Result<Self, OrtError> {
-        // todo customize these with 
-        let mut embedding_model = models::EmbeddingModel::new()?;
+    // model_key picks a models.json entry other than the default embedding model,
+    // index_kind brute force or HNSW with its recall/latency parameters
+    pub fn new(
+        location: &str,
+        docs: &[Document],
+        chunk_size: usize,
+        overlap: usize,
+        model_key: Option<&str>,
+        index_kind: &IndexKind,
+    ) -> Result<Self, OrtError> {
+        let config = embedding_config(model_key)?;
+        let embedding_model = models::EmbeddingModel::new(&config)?;
 
+        let source = SourceInfo {
+            location: location.to_string(),
+            chunk_size,
+            overlap,
+            model: config.id,
+            model_key: model_key.map(|k| k.to_string()),
+        };
+        let index = index::build_index(index_kind, embedding_model.embeddings.view());
 
-        let chunks = utils::chunk_text(&data, chunk_size, chunks).unwrap();
+        let mut vec_db = Self {
+            embedding_model,
+            chunks: vec![],
+            source,
+            index,
+            bm25: Bm25Index::new(),
+        };
         println!("SETTING EMBEDDINGS...");
-        embedding_model.set_embeddings(&chunks);
+        vec_db.add_documents(docs)?;
         println!("DONE...");
 
+        Ok(vec_db)
+    }
+
+    // restores a VecDB written by `save` without re-embedding the chunks
+    pub fn load(path: &Path) -> Result<Self, OrtError> {
+        let content = fs::read_to_string(path).map_err(|e| OrtError::new(e.to_string()))?;
+        let file: VecDBFile = serde_json::from_str(&content).map_err(|e| OrtError::new(e.to_string()))?;
+        if file.version != VECDB_FORMAT_VERSION {
+            return Err(OrtError::new(format!(
+                "unsupported index version {} (expected {})",
+                file.version, VECDB_FORMAT_VERSION
+            )));
+        }
+
+        let embeddings = Array2::from_shape_vec((file.chunks.len(), file.dim), file.embeddings)
+            .map_err(|e| OrtError::new(e.to_string()))?;
+
+        let config = embedding_config(file.source.model_key.as_deref())?;
+        if config.id != file.source.model {
+            return Err(OrtError::new(format!(
+                "index was built with model {}, but {} is configured",
+                file.source.model, config.id
+            )));
+        }
+        let mut embedding_model = models::EmbeddingModel::new(&config)?;
+        embedding_model.embeddings = embeddings;
+        let index = index::build_index(&file.index, embedding_model.embeddings.view());
+        let bm25 = Bm25Index::build(file.chunks.iter().map(|c| c.text.as_str()));
+
         Ok(Self {
             embedding_model,
-            chunks
+            chunks: file.chunks,
+            source: file.source,
+            index,
+            bm25,
         })
     }
 
+    pub fn save(&self, path: &Path) -> Result<(), OrtError> {
+        let embeddings = &self.embedding_model.embeddings;
+        let file = VecDBFile {
+            version: VECDB_FORMAT_VERSION,
+            source: self.source.clone(),
+            index: self.index.kind(),
+            chunks: self.chunks.clone(),
+            dim: embeddings.ncols(),
+            embeddings: embeddings.iter().cloned().collect(),
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| OrtError::new(e.to_string()))?;
+        }
+        let content = serde_json::to_string(&file).map_err(|e| OrtError::new(e.to_string()))?;
+        fs::write(path, content).map_err(|e| OrtError::new(e.to_string()))?;
+        Ok(())
+    }
+
+    pub fn source(&self) -> &SourceInfo {
+        &self.source
+    }
+
+    pub fn document_ids(&self) -> Vec<&str> {
+        let mut ids: Vec<&str> = self.chunks.iter().map(|c| c.doc_id.as_str()).collect();
+        ids.dedup();
+        ids
+    }
+
+    // a document's text put back together from its chunks
+    pub fn document_text(&self, doc_id: &str) -> Option<String> {
+        let parts: Vec<&Chunk> = self.chunks.iter().filter(|c| c.doc_id == doc_id).collect();
+        (!parts.is_empty()).then(|| stitch_chunks(parts))
+    }
+
+    // distinct values of a metadata key across the chunks
+    pub fn meta_values(&self, key: &str) -> Vec<String> {
+        let mut values: Vec<String> = self.chunks.iter()
+            .filter_map(|c| c.metadata.get(key).map(|v| v.to_string()))
+            .collect();
+        values.sort();
+        values.dedup();
+        values
+    }
+
+    pub fn contains_document(&self, doc_id: &str) -> bool {
+        self.chunks.iter().any(|c| c.doc_id == doc_id)
+    }
+
+    // chunks and embeds a new document, returns the number of chunks added
+    pub fn add_document(&mut self, doc: &Document) -> Result<usize, OrtError> {
+        self.add_documents(std::slice::from_ref(doc))
+    }
+
+    // like add_document, but embeds the chunks of all documents in one pass
+    pub fn add_documents(&mut self, docs: &[Document]) -> Result<usize, OrtError> {
+        if let Some(doc) = docs.iter().find(|d| self.contains_document(&d.id)) {
+            return Err(OrtError::new(format!("document {} already exists", doc.id)));
+        }
+        let mut chunks = vec![];
+        for doc in docs {
+            chunks.extend(self.chunk(doc)?);
+        }
+        let embeddings = embed_chunks(&mut self.embedding_model, chunks.iter().collect())?;
+        let n = chunks.len();
+        self.append(chunks, embeddings);
+        Ok(n)
+    }
+
+    // replaces a document, only chunks whose text changed are re-embedded.
+    // returns the number of chunks that had to be embedded
+    pub fn update_document(&mut self, doc: &Document) -> Result<usize, OrtError> {
+        self.update_documents(std::slice::from_ref(doc))
+    }
+
+    // like update_document for many documents: unchanged ones are left alone, the stale
+    // chunks of the others are embedded in one pass and the index is rebuilt once
+    pub fn update_documents(&mut self, docs: &[Document]) -> Result<usize, OrtError> {
+        Ok(self.apply_sync(docs, false)?.0)
+    }
+
+    // update_documents that also drops the documents missing from `docs`, for bringing
+    // the db in line with a fresh parse of the source. returns (chunks embedded, documents removed)
+    pub fn sync_documents(&mut self, docs: &[Document]) -> Result<(usize, usize), OrtError> {
+        self.apply_sync(docs, true)
+    }
+
+    fn apply_sync(&mut self, docs: &[Document], drop_missing: bool) -> Result<(usize, usize), OrtError> {
+        let new = docs.iter()
+            .map(|d| Ok((d.id.as_str(), self.chunk(d)?)))
+            .collect::<Result<Vec<_>, OrtError>>()?;
+        let plan = plan_sync(&self.chunks, new, drop_missing);
+        if plan.drop.is_empty() {
+            return Ok((0, 0));
+        }
+
+        let stale: Vec<&Chunk> = plan.chunks.iter().zip(&plan.reuse)
+            .filter(|(_, row)| row.is_none())
+            .map(|(c, _)| c)
+            .collect();
+        let n_stale = stale.len();
+        let fresh = if stale.is_empty() { Array2::zeros((0, 0)) } else { embed_chunks(&mut self.embedding_model, stale)? };
+
+        // reused vectors are copied out before the rows shift
+        let dim = self.embedding_model.embeddings.ncols().max(fresh.ncols());
+        let mut embeddings = Array2::<f32>::zeros((plan.chunks.len(), dim));
+        let mut fresh_rows = fresh.axis_iter(Axis(0));
+        for (i, row) in plan.reuse.iter().enumerate() {
+            match row {
+                Some(row) => embeddings.row_mut(i).assign(&self.embedding_model.embeddings.row(*row)),
+                None => embeddings.row_mut(i).assign(&fresh_rows.next().unwrap()),
+            }
+        }
+
+        self.remove(|id| plan.drop.contains(id));
+        self.append(plan.chunks, embeddings);
+        Ok((n_stale, plan.removed))
+    }
+
+    // returns false if there was no such document
+    pub fn delete_document(&mut self, doc_id: &str) -> bool {
+        if !self.contains_document(doc_id) {
+            return false;
+        }
+        self.remove(|id| id == doc_id);
+        true
+    }
+
+    // like delete_document for many documents, the index is rebuilt once.
+    // returns the number of chunks removed
+    pub fn delete_documents(&mut self, doc_ids: &[&str]) -> usize {
+        let n = self.chunks.len();
+        let doc_ids: HashSet<&str> = doc_ids.iter().copied().collect();
+        self.remove(|id| doc_ids.contains(id));
+        n - self.chunks.len()
+    }
+
+    fn chunk(&self, doc: &Document) -> Result<Vec<Chunk>, OrtError> {
+        chunk_document(doc, self.source.chunk_size, self.source.overlap)
+    }
+
+    fn append(&mut self, chunks: Vec<Chunk>, embeddings: Array2<f32>) {
+        if chunks.is_empty() {
+            return;
+        }
+        let start = self.chunks.len();
+        let matrix = &mut self.embedding_model.embeddings;
+        if matrix.nrows() == 0 {
+            *matrix = embeddings;
+        } else {
+            *matrix = ndarray::concatenate(Axis(0), &[matrix.view(), embeddings.view()]).unwrap();
+        }
+        self.chunks.extend(chunks);
+        for row in start..self.chunks.len() {
+            self.index.add(self.embedding_model.embeddings.view(), row);
+            self.bm25.add(&self.chunks[row].text);
+        }
+    }
+
+    // drops the rows of matching documents; row numbers shift so the index is rebuilt from the stored vectors
+    fn remove(&mut self, drop: impl Fn(&str) -> bool) {
+        let keep: Vec<usize> = (0..self.chunks.len())
+            .filter(|&i| !drop(&self.chunks[i].doc_id))
+            .collect();
+        if keep.len() == self.chunks.len() {
+            return;
+        }
+        self.embedding_model.embeddings = self.embedding_model.embeddings.select(Axis(0), &keep);
+        self.chunks.retain(|c| !drop(&c.doc_id));
+        self.index = index::build_index(&self.index.kind(), self.embedding_model.embeddings.view());
+        self.bm25 = Bm25Index::build(self.chunks.iter().map(|c| c.text.as_str()));
+    }
+
+    // filters are checked against chunk metadata while the index is searched
     pub fn find_top_n_sim(&mut self, 
                        query: &str, 
                        n: usize, 
-       ) -> Result<Vec<(f32, &str)>, OrtError> {
-        
-        let (ids, mask) = self.embedding_model.encode(&vec![query.to_string()])?;
-        let query_embeddings = self.embedding_model.forward(ids, mask).unwrap();
-        let query_vec = query_embeddings.index_axis(Axis(0), 0).into_owned();
+                       filters: &[Filter],
+       ) -> Result<Vec<(f32, Chunk)>, OrtError> {
+        let options = QueryOptions { filters: filters.to_vec(), ..Default::default() };
+        self.search(query, n, &options)
+    }
+
+    // ranks chunks semantically, lexically (BM25) or by fusing both rankings,
+    // then optionally merges overlapping windows and diversifies with MMR.
+    // scores are only comparable within one mode
+    pub fn search(&mut self,
+                  query: &str,
+                  n: usize,
+                  options: &QueryOptions,
+       ) -> Result<Vec<(f32, Chunk)>, OrtError> {
+        let query_vec = match (&options.mode, options.mmr) {
+            (RetrievalMode::Lexical, None) => Array1::zeros(0),
+            _ => self.embed_query(query)?,
+        };
+        let chunks = &self.chunks;
+        let filter = |i: usize| document::matches_all(&options.filters, &chunks[i].metadata);
+
+        // post-processing needs a deeper pool to choose from
+        let fetch = match (options.mmr, options.collapse_overlaps) {
+            (None, false) => n,
+            (mmr, _) => (n * 4).max(mmr.map_or(0, |m| m.candidates)),
+        };
+
+        let rows = match &options.mode {
+            RetrievalMode::Semantic => {
+                self.index.search(self.embedding_model.embeddings.view(), query_vec.view(), fetch, &filter)
+            }
+            RetrievalMode::Lexical => self.bm25.search(query, fetch, &filter),
+            RetrievalMode::Hybrid(fusion) => {
+                // over-fetch so that chunks ranked low by one scorer can still surface
+                let candidates = fetch * 4;
+                let semantic = self.index.search(self.embedding_model.embeddings.view(), query_vec.view(), candidates, &filter);
+                let lexical = self.bm25.search(query, candidates, &filter);
+                bm25::fuse(&semantic, &lexical, fusion, fetch)
+            }
+        };
+
+        // each hit is a group of rows, the first one being the best scoring
+        let mut hits: Vec<(f32, Vec<usize>)> = if options.collapse_overlaps {
+            group_overlapping(chunks, &rows)
+        } else {
+            rows.into_iter().map(|(score, i)| (score, vec![i])).collect()
+        };
+
+        match options.mmr {
+            Some(mmr) => {
+                let representatives: Vec<usize> = hits.iter().map(|h| h.1[0]).collect();
+                let picked = index::mmr_select(self.embedding_model.embeddings.view(), query_vec.view(), &representatives, n, mmr.lambda);
+                hits = picked.into_iter().map(|i| hits[i].clone()).collect();
+            }
+            None => hits.truncate(n),
+        }
+
+        Ok(hits.into_iter().map(|(score, rows)| (score, merge_chunks(chunks, &rows))).collect())
+    }
+
+    fn embed_query(&mut self, query: &str) -> Result<Array1<f32>, OrtError> {
+        self.embedding_model.embed_query(query)
+    }
+}
+
+// orders retrieved chunks by their cross-encoder scores, best first, and keeps top_n
+fn rerank(mut results: Vec<Retrieved>, scores: &[f32], top_n: usize) -> Vec<Retrieved> {
+    for (r, &s) in results.iter_mut().zip(scores) {
+        r.rerank_score = Some(s);
+    }
+    results.sort_by(|a, b| b.rerank_score.partial_cmp(&a.rerank_score).unwrap_or(std::cmp::Ordering::Equal));
+    results.truncate(top_n);
+    results
+}
+
+// what a sync has to change: the documents whose rows go, and the chunks that replace
+// them, each with the old row whose vector it can reuse
+#[derive(Debug, Default)]
+struct SyncPlan {
+    drop: HashSet<String>,
+    // documents dropped without a replacement
+    removed: usize,
+    chunks: Vec<Chunk>,
+    reuse: Vec<Option<usize>>,
+}
+
+// compares freshly chunked documents with the stored chunks. a document is only
+// replaced if its chunks differ, and only chunks with new text lack a reusable row
+fn plan_sync(old: &[Chunk], docs: Vec<(&str, Vec<Chunk>)>, drop_missing: bool) -> SyncPlan {
+    let mut old_rows: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, c) in old.iter().enumerate() {
+        old_rows.entry(c.doc_id.as_str()).or_default().push(i);
+    }
+    let mut plan = SyncPlan::default();
+    if drop_missing {
+        let incoming: HashSet<&str> = docs.iter().map(|(id, _)| *id).collect();
+        plan.drop = old_rows.keys().filter(|id| !incoming.contains(*id)).map(|id| id.to_string()).collect();
+        plan.removed = plan.drop.len();
+    }
+    for (id, chunks) in docs {
+        let rows = old_rows.get(id).map_or(&[][..], |rows| rows.as_slice());
+        let unchanged = rows.len() == chunks.len() && rows.iter().zip(&chunks).all(|(&row, c)| {
+            old[row].text == c.text && old[row].offset == c.offset && old[row].metadata == c.metadata
+        });
+        if unchanged {
+            continue;
+        }
+        let texts: HashMap<&str, usize> = rows.iter().map(|&row| (old[row].text.as_str(), row)).collect();
+        plan.reuse.extend(chunks.iter().map(|c| texts.get(c.text.as_str()).copied()));
+        plan.chunks.extend(chunks);
+        plan.drop.insert(id.to_string());
+    }
+    plan
+}
+
+fn embedding_config(model_key: Option<&str>) -> Result<models::ModelConfig, OrtError> {
+    match model_key {
+        Some(key) => models::ModelConfig::from_key(models::ModelRole::Embedding, key),
+        None => models::ModelConfig::from_env(models::ModelRole::Embedding),
+    }
+}
+
+// embeds chunk texts, reporting the ones that went over the model's token limit
+fn embed_chunks(model: &mut models::EmbeddingModel, chunks: Vec<&Chunk>) -> Result<Array2<f32>, OrtError> {
+    let texts: Vec<String> = chunks.iter().map(|c| c.text.clone()).collect();
+    let embeddings = model.embed(&texts)?;
+    let config = model.config();
+    for &(i, tokens) in &model.truncated {
+        println!(
+            "Chunk {}@{} has {} tokens, over the {} limit ({:?}), consider a smaller chunk size",
+            chunks[i].doc_id, chunks[i].offset, tokens, config.max_tokens, config.truncation
+        );
+    }
+    Ok(embeddings)
+}
 
-        let mut res: Vec<(f32, &str)> = vec![];
-        for (embeddings, sentence) in self.embedding_model.embeddings.axis_iter(Axis(0)).zip(self.chunks.iter()) {
-            let dot_product: f32 = query_vec.iter().zip(embeddings.iter()).map(|(a,b)| a * b).sum();
-            res.push((dot_product, sentence));
+fn chunk_document(doc: &Document, chunk_size: usize, overlap: usize) -> Result<Vec<Chunk>, OrtError> {
+    let pieces = utils::chunk_text_with_offsets(&doc.text, chunk_size, overlap)
+        .map_err(|e| OrtError::new(e.to_string()))?;
+
+    let chunks = pieces.into_iter().map(|(offset, text)| {
+        let mut metadata = doc.metadata.clone();
+        // narrow a document's line range down to the lines this chunk covers
+        if let Some(&MetaValue::Int(start_line)) = doc.metadata.get("start_line") {
+            let first = start_line + doc.text[..offset].matches('\n').count() as i64;
+            let last = first + text.trim_end().matches('\n').count() as i64;
+            metadata.insert("start_line".to_string(), MetaValue::Int(first));
+            metadata.insert("end_line".to_string(), MetaValue::Int(last));
+        }
+        Chunk { doc_id: doc.id.clone(), text, offset, metadata }
+    }).collect();
+    Ok(chunks)
+}
+
+// the text all chunks of one document cover. the splitter trims the whitespace between
+// chunks, it comes back as a single newline
+fn stitch_chunks(mut parts: Vec<&Chunk>) -> String {
+    parts.sort_by_key(|c| c.offset);
+    let mut text = String::new();
+    let mut end = 0;
+    for part in parts {
+        if part.offset > end && !text.is_empty() {
+            text.push('\n');
+        }
+        let skip = end.saturating_sub(part.offset);
+        if skip < part.text.len() && part.text.is_char_boundary(skip) {
+            text.push_str(&part.text[skip..]);
+        }
+        end = end.max(part.offset + part.text.len());
+    }
+    text
+}
+
+// groups ranked rows whose chunks overlap within the same document, keeping rank order
+fn group_overlapping(chunks: &[Chunk], rows: &[(f32, usize)]) -> Vec<(f32, Vec<usize>)> {
+    // (score, rows, byte range covered so far)
+    let mut groups: Vec<(f32, Vec<usize>, usize, usize)> = vec![];
+    for &(score, row) in rows {
+        let c = &chunks[row];
+        let (start, end) = (c.offset, c.offset + c.text.len());
+        let existing = groups.iter_mut().find(|g| {
+            chunks[g.1[0]].doc_id == c.doc_id && start <= g.3 && g.2 <= end
+        });
+        match existing {
+            Some(g) => {
+                g.1.push(row);
+                g.2 = g.2.min(start);
+                g.3 = g.3.max(end);
+            }
+            None => groups.push((score, vec![row], start, end)),
+        }
+    }
+    groups.into_iter().map(|g| (g.0, g.1)).collect()
+}
+
+// stitches overlapping chunks of one document back into a single chunk
+fn merge_chunks(chunks: &[Chunk], rows: &[usize]) -> Chunk {
+    let mut parts: Vec<&Chunk> = rows.iter().map(|&r| &chunks[r]).collect();
+    parts.sort_by_key(|c| c.offset);
+
+    let mut merged = parts[0].clone();
+    for part in &parts[1..] {
+        let merged_end = merged.offset + merged.text.len();
+        let part_end = part.offset + part.text.len();
+        if part_end <= merged_end {
+            continue;
+        }
+        match merged_end.checked_sub(part.offset) {
+            Some(skip) if part.text.is_char_boundary(skip) => merged.text.push_str(&part.text[skip..]),
+            _ => {
+                merged.text.push_str("\n...\n");
+                merged.text.push_str(&part.text);
+            }
+        }
+        if let Some(end_line) = part.metadata.get("end_line") {
+            merged.metadata.insert("end_line".to_string(), end_line.clone());
+        }
+    }
+    merged
+}
+
+
+// number of chunks a query returns when no reranker is configured
+const DEFAULT_TOP_N: usize = 4;
+
+// second stage: the cross-encoder rescores `candidates` retrieved chunks and keeps the best `top_n`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RerankConfig {
+    pub candidates: usize,
+    pub top_n: usize,
+}
+
+impl Default for RerankConfig {
+    fn default() -> Self {
+        Self {
+            candidates: 20,
+            top_n: DEFAULT_TOP_N,
         }
-        res.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
-        Ok(res[..n].to_vec())    
-    }    
+    }
+}
+
+// extractive QA over the retrieved chunks instead of returning them as is
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QaConfig {
+    pub n_best: usize,
+    // in tokens
+    pub max_answer_len: usize,
+    // below this the retrieved chunks are returned instead
+    pub min_confidence: f32,
+}
+
+impl Default for QaConfig {
+    fn default() -> Self {
+        Self {
+            n_best: 3,
+            max_answer_len: 30,
+            min_confidence: 0.3,
+        }
+    }
+}
+
+// generates text from a prompt built out of the retrieved chunks
+pub trait LlmBackend {
+    fn generate(&mut self, prompt: &str) -> Result<String, OrtError>;
+}
+
+// a chat completions endpoint, e.g. llama.cpp's server, ollama or vllm.
+// configured with MINI_RAG_LLM_URL, MINI_RAG_LLM_MODEL and MINI_RAG_LLM_API_KEY
+pub struct OpenAiBackend {
+    // up to and including the version, e.g. http://localhost:8080/v1
+    pub base_url: String,
+    pub model: String,
+    pub api_key: Option<String>,
+    pub max_tokens: usize,
+    pub temperature: f32,
+}
+
+impl OpenAiBackend {
+    pub fn new(base_url: &str, model: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model: model.to_string(),
+            api_key: None,
+            max_tokens: 512,
+            temperature: 0.0,
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let url = env::var("MINI_RAG_LLM_URL").unwrap_or_else(|_| "http://localhost:8080/v1".to_string());
+        let model = env::var("MINI_RAG_LLM_MODEL").unwrap_or_else(|_| "local".to_string());
+        let mut backend = Self::new(&url, &model);
+        backend.api_key = env::var("MINI_RAG_LLM_API_KEY").ok();
+        backend
+    }
+}
+
+impl LlmBackend for OpenAiBackend {
+    fn generate(&mut self, prompt: &str) -> Result<String, OrtError> {
+        let body = serde_json::json!({
+            "model": self.model,
+            "messages": [{ "role": "user", "content": prompt }],
+            "max_tokens": self.max_tokens,
+            "temperature": self.temperature,
+        });
+        let mut request = ureq::post(&format!("{}/chat/completions", self.base_url))
+            .header("Content-Type", "application/json");
+        if let Some(key) = &self.api_key {
+            request = request.header("Authorization", &format!("Bearer {}", key));
+        }
+        let content = request.send(body.to_string())
+            .and_then(|mut res| res.body_mut().read_to_string())
+            .map_err(|e| OrtError::new(format!("{}: {}", self.base_url, e)))?;
+
+        let res: serde_json::Value = serde_json::from_str(&content).map_err(|e| OrtError::new(e.to_string()))?;
+        res["choices"][0]["message"]["content"].as_str()
+            .map(|text| text.trim().to_string())
+            .ok_or_else(|| OrtError::new(format!("unexpected completion response: {}", content)))
+    }
+}
+
+// generates in-process with an exported decoder, no network needed
+pub struct LocalBackend {
+    model: models::DecoderModel,
+    pub config: models::GenerateConfig,
+}
+
+impl LocalBackend {
+    pub fn new(config: models::GenerateConfig) -> Result<Self, OrtError> {
+        let model = models::DecoderModel::new(&models::ModelConfig::from_env(models::ModelRole::Decoder)?)?;
+        Ok(Self { model, config })
+    }
+}
+
+impl LlmBackend for LocalBackend {
+    fn generate(&mut self, prompt: &str) -> Result<String, OrtError> {
+        self.model.generate(prompt, &self.config)
+    }
+}
+
+// answers without a model: the fixed reply if set, otherwise a summary of the prompt.
+// keeps every prompt it was given so tests can check what would have been sent
+#[derive(Debug, Clone, Default)]
+pub struct MockBackend {
+    pub reply: Option<String>,
+    pub prompts: Vec<String>,
+}
+
+impl LlmBackend for MockBackend {
+    fn generate(&mut self, prompt: &str) -> Result<String, OrtError> {
+        self.prompts.push(prompt.to_string());
+        Ok(match &self.reply {
+            Some(reply) => reply.clone(),
+            None => format!("mock answer to a {} line prompt", prompt.lines().count()),
+        })
+    }
 }
 
+// an answer span and the chunk it came from
+#[derive(Debug, Clone)]
+pub struct ScoredAnswer {
+    pub answer: models::Answer,
+    pub chunk: Chunk,
+}
+
+// a chunk returned by RAGBase::retrieve along with how it was scored
+#[derive(Debug, Clone)]
+pub struct Retrieved {
+    pub chunk: Chunk,
+    pub score: f32,
+    pub rerank_score: Option<f32>,
+}
 
 pub struct RAGBase {
     bert: models::BertModel,
-    vec_db: VecDB
+    vec_db: VecDB,
+    options: QueryOptions,
+    reranker: Option<(models::RerankerModel, RerankConfig)>,
+    qa: Option<QaConfig>,
+    llm: Option<Box<dyn LlmBackend>>,
+    prompt: PromptTemplate,
+    // (question, answer) of the last synthesized turns, fed back through {history}
+    history: Vec<(String, String)>,
 }
 
-#[allow(dead_code)]
-// do I need this?
-enum AgentSpecialization {
+// synthesized turns kept for the {history} placeholder
+const MAX_HISTORY: usize = 4;
+
+// which agent a source gets, AgentBuilder detects it unless told otherwise
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AgentSpecialization {
     OpenAPI,
     Codebase, // type converter etc
+    Docs, // markdown, text and other prose
     Educational, // e.g., a programming primitives "how to model composition in Rust, how to start a thread"
     Debugger // accesses a stream of logging
 }
 
+impl AgentSpecialization {
+    const ALL: [AgentSpecialization; 5] = [Self::OpenAPI, Self::Codebase, Self::Docs, Self::Educational, Self::Debugger];
+
+    // the kind stored in the server manifest
+    pub fn key(&self) -> &'static str {
+        match self {
+            Self::OpenAPI => "openapi",
+            Self::Codebase => "codebase",
+            Self::Docs => "docs",
+            Self::Educational => "educational",
+            Self::Debugger => "debugger",
+        }
+    }
+
+    pub fn parse(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.key() == key)
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::OpenAPI => "OpenAPI",
+            Self::Codebase => "Codebase",
+            Self::Docs => "Docs",
+            Self::Educational => "Educational",
+            Self::Debugger => "Debugger",
+        }
+    }
+
+    // operations are matched on exact paths and field names, definitions and docs are
+    // long and read best with their neighbouring windows merged
+    pub fn defaults(&self) -> AgentConfig {
+        let (chunk_size, overlap, mode, collapse_overlaps) = match self {
+            Self::OpenAPI => (600, 100, RetrievalMode::Hybrid(Fusion::default()), true),
+            Self::Codebase => (500, 100, RetrievalMode::Hybrid(Fusion::default()), true),
+            Self::Docs | Self::Educational => (800, 200, RetrievalMode::Semantic, true),
+            Self::Debugger => (400, 80, RetrievalMode::Hybrid(Fusion::default()), true),
+        };
+        AgentConfig { chunk_size, overlap, model: None, mode, collapse_overlaps, index: IndexKind::default() }
+    }
+}
+
+// how an agent chunks, embeds and retrieves its source
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentConfig {
+    pub chunk_size: usize,
+    pub overlap: usize,
+    // models.json entry of the embedding model, None for the default
+    pub model: Option<String>,
+    pub mode: RetrievalMode,
+    pub collapse_overlaps: bool,
+    // brute force, or HNSW for large corpora
+    pub index: IndexKind,
+}
+
 // "Agent" in the sense that it has functionality beyond LLM io
 pub trait AgentI where {
     fn execute(&mut self, user_input: &str) -> Result<String, OrtError>;
 
+    // persists the agent's index so it can be restored without re-embedding
+    fn save(&self, path: &Path) -> Result<(), OrtError>;
+
+    // re-reads the agent's source and re-embeds whatever changed
+    fn refresh(&mut self) -> Result<String, OrtError> {
+        Ok("Refresh not supported".to_string())
+    }
+
+    // the retrieval pipeline the settings below are forwarded to
+    fn rag_base_mut(&mut self) -> &mut RAGBase;
+
+    // filters, retrieval mode and diversification used by every following query
+    fn options_mut(&mut self) -> &mut QueryOptions {
+        &mut self.rag_base_mut().options
+    }
+
+    // enables cross-encoder reranking, None turns it off
+    fn set_reranker(&mut self, config: Option<RerankConfig>) -> Result<(), OrtError> {
+        self.rag_base_mut().set_reranker(config)
+    }
+
+    // answers with extracted spans instead of chunks, None turns it off
+    fn set_qa(&mut self, config: Option<QaConfig>) {
+        self.rag_base_mut().set_qa(config)
+    }
+
+    // synthesizes answers from the retrieved chunks with an LLM, None turns it off
+    fn set_llm(&mut self, backend: Option<Box<dyn LlmBackend>>) {
+        self.rag_base_mut().set_llm(backend)
+    }
+
+    // switches to another named prompt template
+    fn set_prompt(&mut self, name: &str) -> Result<(), OrtError> {
+        self.rag_base_mut().set_prompt(name)
+    }
+
+    // limits answers to a time range, see logs::TimeWindow::parse. "off" removes it
+    fn set_window(&mut self, _spec: &str) -> Result<String, OrtError> {
+        Ok("Time windows not supported".to_string())
+    }
+
+    // how many records or how much time the index keeps
+    fn set_retention(&mut self, _retention: logs::Retention) -> Result<String, OrtError> {
+        Ok("Retention not supported".to_string())
+    }
 }
 
 pub struct OpenAPIAgent {
     rag_base: RAGBase
 }
 
-#[allow(dead_code)]
 pub struct CodebaseAgent {
     rag_base: RAGBase
 }
 
+pub struct DocsAgent {
+    rag_base: RAGBase
+}
+
+// answers "how do I ..." with the best matching snippet of a curated corpus
+pub struct EducationalAgent {
+    rag_base: RAGBase
+}
+
+// follows log files, stdin or a unix socket; new records are embedded before each question
+pub struct DebuggerAgent {
+    rag_base: RAGBase,
+    tail: logs::LogTail,
+    retention: logs::Retention,
+    window: Option<logs::TimeWindow>,
+    // timestamp of the newest record, what "14:00" or "last 10m" are relative to
+    newest: Option<i64>,
+}
+
+// where a debugger agent's tail stopped, next to its index
+#[derive(Debug, Serialize, Deserialize)]
+struct DebuggerState {
+    inputs: Vec<logs::InputState>,
+    retention: logs::Retention,
+}
+
+// this class constructs a different agent depending on user input: a url, file, directory
+// or glob, plus whatever the user wants to change from the specialization's defaults
+#[derive(Debug, Clone, Default)]
 pub struct AgentBuilder {
-    // this class constructs a different agent depending on user input 
+    source: String,
+    specialization: Option<AgentSpecialization>,
+    chunk_size: Option<usize>,
+    overlap: Option<usize>,
+    model: Option<String>,
+    mode: Option<RetrievalMode>,
+    index: Option<IndexKind>,
+    // the source's content when detection had to fetch it, build reuses it
+    fetched: OnceCell<String>,
+}
+
+impl AgentBuilder {
+    pub fn new(source: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    // skips detection
+    pub fn specialization(mut self, specialization: AgentSpecialization) -> Self {
+        self.specialization = Some(specialization);
+        self
+    }
+
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    pub fn overlap(mut self, overlap: usize) -> Self {
+        self.overlap = Some(overlap);
+        self
+    }
+
+    pub fn model(mut self, model: &str) -> Self {
+        self.model = Some(model.to_string());
+        self
+    }
+
+    pub fn mode(mut self, mode: RetrievalMode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    pub fn index(mut self, index: IndexKind) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    // one key=value override as typed by the user: kind, chunk_size, overlap, model, mode
+    // or index, where words are joined by ':' ("mode=hybrid:weighted:0.7", "index=hnsw:16:200:64")
+    pub fn set(self, key: &str, value: &str) -> Result<Self, String> {
+        let number = || value.parse::<usize>().map_err(|_| format!("'{}' is not a number", value));
+        match key {
+            "kind" => AgentSpecialization::parse(value)
+                .map(|s| self.specialization(s))
+                .ok_or_else(|| format!("unknown agent kind '{}'", value)),
+            "chunk_size" => Ok(self.chunk_size(number()?)),
+            "overlap" => Ok(self.overlap(number()?)),
+            "model" => Ok(self.model(value)),
+            "mode" => Ok(self.mode(RetrievalMode::parse(&value.replace(':', " "))?)),
+            "index" => Ok(self.index(IndexKind::parse(&value.replace(':', " "))?)),
+            _ => Err(format!("unknown setting '{}'", key)),
+        }
+    }
+
+    // the forced specialization, or the one the source looks like
+    pub fn kind(&self) -> Result<AgentSpecialization, OrtError> {
+        match self.specialization {
+            Some(specialization) => Ok(specialization),
+            None => {
+                let (specialization, content) = detect(&self.source)?;
+                if let Some(content) = content {
+                    let _ = self.fetched.set(content);
+                }
+                Ok(specialization)
+            }
+        }
+    }
+
+    // the specialization's defaults with the overrides applied
+    pub fn config(&self, specialization: AgentSpecialization) -> AgentConfig {
+        let defaults = specialization.defaults();
+        AgentConfig {
+            chunk_size: self.chunk_size.unwrap_or(defaults.chunk_size),
+            overlap: self.overlap.unwrap_or(defaults.overlap),
+            model: self.model.clone().or(defaults.model),
+            mode: self.mode.clone().unwrap_or(defaults.mode),
+            collapse_overlaps: defaults.collapse_overlaps,
+            index: self.index.clone().unwrap_or(defaults.index),
+        }
+    }
+
+    pub fn build(&self) -> Result<Box<dyn AgentI>, OrtError> {
+        let specialization = self.kind()?;
+        let config = self.config(specialization);
+        if config.overlap >= config.chunk_size {
+            return Err(OrtError::new(format!("overlap {} must be smaller than chunk_size {}", config.overlap, config.chunk_size)));
+        }
+        let mut agent: Box<dyn AgentI> = match specialization {
+            AgentSpecialization::OpenAPI => Box::new(match self.fetched.get() {
+                Some(content) => OpenAPIAgent::from_content(&self.source, content, &config)?,
+                None => OpenAPIAgent::new(&self.source, &config)?,
+            }),
+            AgentSpecialization::Codebase => Box::new(CodebaseAgent::new(&self.source, &config)?),
+            AgentSpecialization::Docs => Box::new(match self.fetched.get() {
+                Some(content) => DocsAgent::from_content(&self.source, content, &config)?,
+                None => DocsAgent::new(&self.source, &config)?,
+            }),
+            AgentSpecialization::Educational => Box::new(EducationalAgent::new(&self.source, &config)?),
+            AgentSpecialization::Debugger => Box::new(DebuggerAgent::new(&self.source, &config)?),
+        };
+        apply_config(agent.options_mut(), &config);
+        Ok(agent)
+    }
+
+    // restores a saved agent with the retrieval settings it was built with, chunking
+    // and model come from the index itself
+    pub fn load(specialization: AgentSpecialization, path: &Path, config: &AgentConfig) -> Result<Box<dyn AgentI>, OrtError> {
+        let mut agent: Box<dyn AgentI> = match specialization {
+            AgentSpecialization::OpenAPI => Box::new(OpenAPIAgent::load(path)?),
+            AgentSpecialization::Codebase => Box::new(CodebaseAgent::load(path)?),
+            AgentSpecialization::Docs => Box::new(DocsAgent::load(path)?),
+            AgentSpecialization::Educational => Box::new(EducationalAgent::load(path)?),
+            AgentSpecialization::Debugger => Box::new(DebuggerAgent::load(path)?),
+        };
+        apply_config(agent.options_mut(), config);
+        Ok(agent)
+    }
+}
+
+fn apply_config(options: &mut QueryOptions, config: &AgentConfig) {
+    options.mode = config.mode.clone();
+    options.collapse_overlaps = config.collapse_overlaps;
+}
+
+// logs (stdin, unix: sockets, .log files) get a debugger, globs are docs, directories
+// are snippet corpora when at least half their files are tagged, codebases when most
+// of their files are code, files and urls are OpenAPI when they look like a spec.
+// the content of a fetched file or url is returned along
+fn detect(source: &str) -> Result<(AgentSpecialization, Option<String>), OrtError> {
+    if logs::is_log_source(source) {
+        return Ok((AgentSpecialization::Debugger, None));
+    }
+    if !utils::is_url(source) && utils::is_glob(source) {
+        return Ok((AgentSpecialization::Docs, None));
+    }
+    let path = Path::new(source.strip_prefix("file://").unwrap_or(source));
+    if path.is_dir() {
+        let files = utils::walk_files(path).map_err(|e| OrtError::new(format!("{}: {}", source, e)))?;
+        let count = |extensions: &[&str]| files.iter()
+            .filter(|f| f.extension().and_then(|e| e.to_str()).is_some_and(|e| extensions.contains(&e)))
+            .count();
+        let code = count(&grep::CODE_EXTENSIONS);
+        let docs = count(&parser::DOC_EXTENSIONS);
+        let tagged = files.iter()
+            .filter(|f| f.extension().and_then(|e| e.to_str()).is_some_and(|e| grep::CODE_EXTENSIONS.contains(&e) || parser::DOC_EXTENSIONS.contains(&e)))
+            .filter(|f| fs::read_to_string(f).is_ok_and(|text| parser::has_snippet_tags(&text)))
+            .count();
+        let specialization = if tagged > 0 && tagged * 2 >= code + docs {
+            AgentSpecialization::Educational
+        } else if code > 0 && code >= docs {
+            AgentSpecialization::Codebase
+        } else {
+            AgentSpecialization::Docs
+        };
+        return Ok((specialization, None));
+    }
+    let content = parser::fetch(source).map_err(|e| OrtError::new(e.to_string()))?;
+    let specialization = if parser::looks_like_openapi(&content) {
+        AgentSpecialization::OpenAPI
+    } else {
+        AgentSpecialization::Docs
+    };
+    Ok((specialization, Some(content)))
 }
 
 impl RAGBase {
-    pub fn new(data: &str, chunk_size: usize, chunks: usize) -> Result<Self, OrtError> {
-        let bert = models::BertModel::new()?;
-        let vec_db = VecDB::new(data, chunk_size, chunks)?;
+    pub fn new(location: &str, docs: &[Document], config: &AgentConfig) -> Result<Self, OrtError> {
+        let bert = models::BertModel::new(&models::ModelConfig::from_env(models::ModelRole::QA)?)?;
+        let vec_db = VecDB::new(location, docs, config.chunk_size, config.overlap, config.model.as_deref(), &config.index)?;
         Ok(Self {
             bert,
             vec_db,
+            options: QueryOptions::default(),
+            reranker: None,
+            qa: None,
+            llm: None,
+            prompt: PromptTemplate::load("default")?,
+            history: vec![],
         })
     }
 
-    pub fn query(&mut self, user_input: &str) -> Result<String, OrtError> {
-        
-        let topn = self.vec_db.find_top_n_sim(user_input, 4)?;
+    pub fn load(path: &Path) -> Result<Self, OrtError> {
+        let bert = models::BertModel::new(&models::ModelConfig::from_env(models::ModelRole::QA)?)?;
+        let vec_db = VecDB::load(path)?;
+        Ok(Self {
+            bert,
+            vec_db,
+            options: QueryOptions::default(),
+            reranker: None,
+            qa: None,
+            llm: None,
+            prompt: PromptTemplate::load("default")?,
+            history: vec![],
+        })
+    }
 
-        // let mut context = "(A URL is another word for name)".to_string();
-        let mut context = String::new();
-        // let mut context = "Answer with the route: \n\n".to_string();
-        for (score, contents) in topn {
+    pub fn save(&self, path: &Path) -> Result<(), OrtError> {
+        self.vec_db.save(path)
+    }
+
+    // brings the index in line with a fresh parse of the source, only changed chunks are
+    // re-embedded. returns (chunks embedded, documents removed)
+    pub fn sync_documents(&mut self, docs: &[Document]) -> Result<(usize, usize), OrtError> {
+        self.vec_db.sync_documents(docs)
+    }
+
+    pub fn set_qa(&mut self, config: Option<QaConfig>) {
+        self.qa = config;
+    }
+
+    pub fn set_llm(&mut self, backend: Option<Box<dyn LlmBackend>>) {
+        self.llm = backend;
+        self.history.clear();
+    }
+
+    pub fn set_prompt(&mut self, name: &str) -> Result<(), OrtError> {
+        self.prompt = PromptTemplate::load(name)?;
+        Ok(())
+    }
+
+    pub fn set_reranker(&mut self, config: Option<RerankConfig>) -> Result<(), OrtError> {
+        self.reranker = match (config, self.reranker.take()) {
+            (None, _) => None,
+            // keep the already loaded model, only the counts changed
+            (Some(config), Some((model, _))) => Some((model, config)),
+            (Some(config), None) => {
+                let model_config = models::ModelConfig::from_env(models::ModelRole::Reranker)?;
+                Some((models::RerankerModel::new(&model_config)?, config))
+            }
+        };
+        Ok(())
+    }
+
+    // first stage retrieval, then the optional cross-encoder pass
+    pub fn retrieve(&mut self, user_input: &str) -> Result<Vec<Retrieved>, OrtError> {
+        let n = self.reranker.as_ref().map_or(DEFAULT_TOP_N, |(_, config)| config.candidates);
+        let topn = self.vec_db.search(user_input, n, &self.options)?;
+        let mut results: Vec<Retrieved> = topn.into_iter()
+            .map(|(score, chunk)| Retrieved { chunk, score, rerank_score: None })
+            .collect();
 
-            // println!("{} {}", score, contents);
-            // context.push_str(&format!("{}", contents));
-            context.push_str(&format!("[{:.1}% match]\n{}\n\n",score*100., contents));
+        if let Some((model, config)) = &mut self.reranker {
+            let passages: Vec<String> = results.iter().map(|r| r.chunk.text.clone()).collect();
+            let scores = model.score(user_input, &passages)?;
+            results = rerank(results, &scores, config.top_n);
         }
+        Ok(results)
+    }
 
-        Ok(context)
+    // the retrieved chunks, or a synthesized answer when an LLM is set, or extracted answers in QA mode
+    pub fn query(&mut self, user_input: &str) -> Result<String, OrtError> {
+        let topn = self.retrieve(user_input)?;
+        if self.llm.is_some() {
+            return Ok(self.synthesize(user_input, &topn));
+        }
+        let Some(config) = self.qa else {
+            return Ok(self.format_chunks(&topn));
+        };
+
+        let answers = self.answer(user_input, &topn, &config)?;
+        match answers.first() {
+            Some(best) if best.answer.score >= config.min_confidence => {
+                let mut res = String::new();
+                for (i, a) in answers.iter().enumerate() {
+                    res.push_str(&format!("{}. {} [{:.1}% | {}]\n", i + 1, a.answer.text, a.answer.score * 100., a.chunk.doc_id));
+                }
+                Ok(res)
+            }
+            best => {
+                let confidence = best.map_or(0.0, |a| a.answer.score);
+                Ok(format!("No confident answer ({:.1}%), closest matches:\n\n{}", confidence * 100., self.format_chunks(&topn)))
+            }
+        }
+    }
 
+    // the LLM's answer followed by the sources it cites,
+    // the chunks themselves if the backend fails
+    fn synthesize(&mut self, question: &str, retrieved: &[Retrieved]) -> String {
+        let chunks: Vec<&Chunk> = retrieved.iter().map(|r| &r.chunk).collect();
+        let prompt = self.prompt.render(question, &chunks, &self.history);
+        let llm = self.llm.as_mut().unwrap();
+        match llm.generate(&prompt) {
+            Ok(text) => {
+                self.history.push((question.to_string(), text.clone()));
+                if self.history.len() > MAX_HISTORY {
+                    self.history.remove(0);
+                }
+                prompt::with_sources(&text, &chunks)
+            }
+            Err(e) => format!("LLM backend failed ({}), closest matches:\n\n{}", e, self.format_chunks(retrieved)),
+        }
     }
 
-    #[allow(dead_code)]
-    fn llm_answer_unstable(&mut self, user_input: &str) -> Result<String, OrtError> {
-        let context = self.query(user_input)?;
-        // context = "The battle of Fuck occurred on March 2 1892".to_string();
-        let input = format!("Question: {} \nContext:\n{}", user_input, context);
-        println!("{}", input);
-        let (ids, mask) = self.bert.encode(input)?;
-        let (start_logits, end_logits) = self.bert.forward(ids, mask)?;
-        // println!("Hello?");
+    // n best spans across all retrieved chunks, most likely first
+    pub fn answer(&mut self, question: &str, retrieved: &[Retrieved], config: &QaConfig) -> Result<Vec<ScoredAnswer>, OrtError> {
+        let mut answers = vec![];
+        for r in retrieved {
+            for answer in self.bert.answer(question, &r.chunk.text, config.n_best, config.max_answer_len)? {
+                answers.push(ScoredAnswer { answer, chunk: r.chunk.clone() });
+            }
+        }
+        answers.sort_by(|a, b| b.answer.score.partial_cmp(&a.answer.score).unwrap_or(std::cmp::Ordering::Equal));
+        answers.truncate(config.n_best);
+        Ok(answers)
+    }
 
-        let answer = self.bert.decode(start_logits, end_logits)?;
-        Ok(answer)
+    fn format_chunks(&self, topn: &[Retrieved]) -> String {
+        let mut context = String::new();
+        for r in topn {
+            let mut header = match self.options.mode {
+                RetrievalMode::Semantic => format!("[{:.1}% match", r.score*100.),
+                _ => format!("[score {:.3}", r.score),
+            };
+            if let Some(rerank_score) = r.rerank_score {
+                header.push_str(&format!(" | rerank {:.2}", rerank_score));
+            }
+            // code chunks say where they are, OpenAPI chunks already start with method and path
+            if r.chunk.metadata.contains_key("file") {
+                header.push_str(&format!(" | {}", prompt::source_label(&r.chunk)));
+                if let Some(name) = r.chunk.metadata.get("name") {
+                    header.push_str(&format!(" {}", name));
+                }
+            }
+            context.push_str(&format!("{}]\n{}\n\n", header, r.chunk.text));
+        }
+        context
     }
 }
 
 impl OpenAPIAgent {
-    pub fn new(url: &str) -> Result<Self, OrtError> {
-        let data = parser::parse_openapi(url).unwrap();
-        let rag_base = RAGBase::new(&data, 150, 70)?;
+    pub fn new(url: &str, config: &AgentConfig) -> Result<Self, OrtError> {
+        let content = parser::fetch(url).map_err(|e| OrtError::new(format!("could not load OpenAPI spec: {}", e)))?;
+        Self::from_content(url, &content, config)
+    }
+
+    // new for a spec already fetched from `url`
+    pub fn from_content(url: &str, content: &str, config: &AgentConfig) -> Result<Self, OrtError> {
+        let docs = parser::parse_openapi_content(url, content).map_err(|e| OrtError::new(format!("could not load OpenAPI spec: {}", e)))?;
+        if !docs.iter().any(|d| d.metadata.contains_key("method")) {
+            return Err(OrtError::new(format!("{} has no operations", url)));
+        }
+        let mut rag_base = RAGBase::new(url, &docs, config)?;
+        rag_base.set_prompt("openapi")?;
         Ok(Self { rag_base})
     }
 
+    pub fn load(path: &Path) -> Result<Self, OrtError> {
+        let mut rag_base = RAGBase::load(path)?;
+        rag_base.set_prompt("openapi")?;
+        Ok(Self { rag_base })
+    }
+
 }
 
 impl AgentI for OpenAPIAgent {
@@ -142,24 +1293,589 @@ impl AgentI for OpenAPIAgent {
         //\x1B[1m{BOLD METHOD}\x1B[0m 
         // \x1B[4m{UNDERLINED URL}\x1B[0m
 
-        Ok(self.rag_base.query(user_input)?)
+        self.rag_base.query(user_input)
+    }
+
+    fn save(&self, path: &Path) -> Result<(), OrtError> {
+        self.rag_base.save(path)
+    }
+
+    fn refresh(&mut self) -> Result<String, OrtError> {
+        let url = self.rag_base.vec_db.source().location.clone();
+        let docs = parser::parse_openapi(&url).map_err(|e| OrtError::new(e.to_string()))?;
+        let (n, removed) = self.rag_base.sync_documents(&docs)?;
+        Ok(format!("Re-embedded {} chunks, removed {} operations and schemas", n, removed))
+    }
+
+    fn rag_base_mut(&mut self) -> &mut RAGBase {
+        &mut self.rag_base
     }
 }
 
 impl CodebaseAgent{
-    pub fn new(root_path: &str) -> Result<Self, OrtError> {
-        let data = parser::parse_codebase(root_path).unwrap();
-        let rag_base = RAGBase::new(&data, 250, 100)?;
+    // indexes every class/struct definition under root_path
+    pub fn new(root_path: &str, config: &AgentConfig) -> Result<Self, OrtError> {
+        let docs = parser::parse_codebase(root_path).map_err(|e| OrtError::new(e.to_string()))?;
+        if docs.is_empty() {
+            return Err(OrtError::new(format!("no class or struct definitions found under {}", root_path)));
+        }
+        let mut rag_base = RAGBase::new(root_path, &docs, config)?;
+        rag_base.set_prompt("codebase")?;
         Ok (
             Self {
                 rag_base
             }
         )
     }
+
+    pub fn load(path: &Path) -> Result<Self, OrtError> {
+        let mut rag_base = RAGBase::load(path)?;
+        rag_base.set_prompt("codebase")?;
+        Ok(Self { rag_base })
+    }
 }
 impl AgentI for CodebaseAgent {
 
+    // ranked definitions headed by their path:start-end, or an answer citing them
     fn execute(&mut self, user_input: &str) -> Result<String, OrtError> {
-        Ok("great".to_string())
+        self.rag_base.query(user_input)
+    }
+
+    fn save(&self, path: &Path) -> Result<(), OrtError> {
+        self.rag_base.save(path)
     }
-}
\ No newline at end of file
+
+    fn refresh(&mut self) -> Result<String, OrtError> {
+        let root = self.rag_base.vec_db.source().location.clone();
+        let docs = parser::parse_codebase(&root).map_err(|e| OrtError::new(e.to_string()))?;
+        let (n, removed) = self.rag_base.sync_documents(&docs)?;
+        Ok(format!("Re-embedded {} chunks, removed {} definitions", n, removed))
+    }
+
+    fn rag_base_mut(&mut self) -> &mut RAGBase {
+        &mut self.rag_base
+    }
+}
+
+impl DocsAgent {
+    // indexes one document per file of a url, file, directory or glob
+    pub fn new(source: &str, config: &AgentConfig) -> Result<Self, OrtError> {
+        let docs = parser::parse_docs(source).map_err(|e| OrtError::new(e.to_string()))?;
+        if docs.is_empty() {
+            return Err(OrtError::new(format!("no documents found at {}", source)));
+        }
+        let rag_base = RAGBase::new(source, &docs, config)?;
+        Ok(Self { rag_base })
+    }
+
+    // new for a single file or url whose text was already fetched
+    pub fn from_content(source: &str, content: &str, config: &AgentConfig) -> Result<Self, OrtError> {
+        let rag_base = RAGBase::new(source, &[parser::line_document(source, content)], config)?;
+        Ok(Self { rag_base })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, OrtError> {
+        Ok(Self { rag_base: RAGBase::load(path)? })
+    }
+}
+
+impl AgentI for DocsAgent {
+
+    fn execute(&mut self, user_input: &str) -> Result<String, OrtError> {
+        self.rag_base.query(user_input)
+    }
+
+    fn save(&self, path: &Path) -> Result<(), OrtError> {
+        self.rag_base.save(path)
+    }
+
+    fn refresh(&mut self) -> Result<String, OrtError> {
+        let source = self.rag_base.vec_db.source().location.clone();
+        let docs = parser::parse_docs(&source).map_err(|e| OrtError::new(e.to_string()))?;
+        let (n, removed) = self.rag_base.sync_documents(&docs)?;
+        Ok(format!("Re-embedded {} chunks, removed {} files", n, removed))
+    }
+
+    fn rag_base_mut(&mut self) -> &mut RAGBase {
+        &mut self.rag_base
+    }
+}
+
+impl DebuggerAgent {
+    // indexes what the sources already hold, within the default retention
+    pub fn new(source: &str, config: &AgentConfig) -> Result<Self, OrtError> {
+        let mut tail = logs::LogTail::open(source).map_err(|e| OrtError::new(e.to_string()))?;
+        let retention = logs::Retention::default();
+        let records = tail.poll().map_err(|e| OrtError::new(e.to_string()))?;
+        let newest = records.iter().filter_map(|r| r.timestamp).max();
+        let docs: Vec<Document> = retention.apply(records, newest).iter().map(|r| r.to_document()).collect();
+        let rag_base = RAGBase::new(source, &docs, config)?;
+        Ok(Self { rag_base, tail, retention, window: None, newest })
+    }
+
+    // reopens the sources and resumes after the last record that was read
+    pub fn load(path: &Path) -> Result<Self, OrtError> {
+        let rag_base = RAGBase::load(path)?;
+        let source = rag_base.vec_db.source().location.clone();
+        let mut tail = logs::LogTail::open(&source).map_err(|e| OrtError::new(e.to_string()))?;
+        let mut retention = logs::Retention::default();
+        if let Ok(content) = fs::read_to_string(state_path(path)) {
+            let state: DebuggerState = serde_json::from_str(&content).map_err(|e| OrtError::new(e.to_string()))?;
+            tail.restore(&state.inputs);
+            retention = state.retention;
+        }
+        let newest = rag_base.vec_db.chunks.iter()
+            .filter_map(|c| match c.metadata.get("timestamp") {
+                Some(MetaValue::Int(t)) => Some(*t),
+                _ => None,
+            })
+            .max();
+        Ok(Self { rag_base, tail, retention, window: None, newest })
+    }
+
+    // embeds the records written since the last call, then applies retention.
+    // returns (records added, records dropped)
+    pub fn ingest(&mut self) -> Result<(usize, usize), OrtError> {
+        let records = self.tail.poll().map_err(|e| OrtError::new(e.to_string()))?;
+        self.newest = records.iter().filter_map(|r| r.timestamp).chain(self.newest).max();
+        let mut docs: Vec<Document> = self.retention.apply(records, self.newest).iter().map(|r| r.to_document()).collect();
+        let expired = self.expired(&docs);
+        docs.retain(|d| !expired.contains(&d.id));
+
+        let vec_db = &mut self.rag_base.vec_db;
+        // a truncated file starts its line numbers over, the new record replaces the old one.
+        // replaced and expired records go in a single rebuild
+        let stored: HashSet<&str> = vec_db.chunks.iter().map(|c| c.doc_id.as_str()).collect();
+        let mut stale: Vec<&str> = docs.iter().map(|d| d.id.as_str()).filter(|id| stored.contains(id)).collect();
+        stale.extend(expired.iter().map(|id| id.as_str()));
+        if !stale.is_empty() {
+            vec_db.delete_documents(&stale);
+        }
+        vec_db.add_documents(&docs)?;
+        Ok((docs.len(), expired.len()))
+    }
+
+    // drops indexed records past the retention limits, returns how many
+    fn enforce_retention(&mut self) -> usize {
+        let expired = self.expired(&[]);
+        let ids: Vec<&str> = expired.iter().map(|id| id.as_str()).collect();
+        self.rag_base.vec_db.delete_documents(&ids);
+        expired.len()
+    }
+
+    // records past the retention limits once `incoming` is stored, stored records
+    // replaced by an incoming one aside. records are stored in the order they arrived
+    fn expired(&self, incoming: &[Document]) -> HashSet<String> {
+        let vec_db = &self.rag_base.vec_db;
+        let replaced: HashSet<&str> = incoming.iter().map(|d| d.id.as_str()).collect();
+        let mut expired: HashSet<String> = HashSet::new();
+        if let (Some(age), Some(newest)) = (self.retention.max_age, self.newest) {
+            for c in &vec_db.chunks {
+                let old = matches!(c.metadata.get("timestamp"), Some(MetaValue::Int(t)) if *t < newest - age);
+                if old && !replaced.contains(c.doc_id.as_str()) {
+                    expired.insert(c.doc_id.clone());
+                }
+            }
+        }
+        if let Some(max) = self.retention.max_records {
+            let live: Vec<&str> = vec_db.document_ids().into_iter()
+                .filter(|id| !expired.contains(*id) && !replaced.contains(id))
+                .chain(incoming.iter().map(|d| d.id.as_str()))
+                .collect();
+            let excess = live.len().saturating_sub(max);
+            expired.extend(live[..excess].iter().map(|id| id.to_string()));
+        }
+        expired
+    }
+
+    // the window set with `window`, or else one the question asks for
+    fn window_for(&self, question: &str) -> Option<logs::TimeWindow> {
+        self.window.or_else(|| logs::TimeWindow::from_question(question, self.newest?))
+    }
+}
+
+fn state_path(index_path: &Path) -> std::path::PathBuf {
+    index_path.with_extension("tail.json")
+}
+
+impl AgentI for DebuggerAgent {
+
+    // the records closest to the question, within its time window if it names one
+    fn execute(&mut self, user_input: &str) -> Result<String, OrtError> {
+        let (added, dropped) = self.ingest()?;
+        if added + dropped > 0 {
+            println!("Ingested {} log records, dropped {}", added, dropped);
+        }
+        let Some(window) = self.window_for(user_input) else {
+            return self.rag_base.query(user_input);
+        };
+        let n_filters = self.rag_base.options.filters.len();
+        self.rag_base.options.filters.extend(window.filters());
+        let res = self.rag_base.query(user_input);
+        self.rag_base.options.filters.truncate(n_filters);
+        Ok(format!("[{}]\n{}", window, res?))
+    }
+
+    fn save(&self, path: &Path) -> Result<(), OrtError> {
+        self.rag_base.save(path)?;
+        let state = DebuggerState { inputs: self.tail.state(), retention: self.retention };
+        let content = serde_json::to_string(&state).map_err(|e| OrtError::new(e.to_string()))?;
+        fs::write(state_path(path), content).map_err(|e| OrtError::new(e.to_string()))
+    }
+
+    fn refresh(&mut self) -> Result<String, OrtError> {
+        let (added, dropped) = self.ingest()?;
+        Ok(format!("Embedded {} new records, dropped {} past retention", added, dropped))
+    }
+
+    fn rag_base_mut(&mut self) -> &mut RAGBase {
+        &mut self.rag_base
+    }
+
+    fn set_window(&mut self, spec: &str) -> Result<String, OrtError> {
+        if spec == "off" {
+            self.window = None;
+            return Ok("Time window off, questions can still name one".to_string());
+        }
+        let Some(newest) = self.newest else {
+            return Ok("No timestamped records yet".to_string());
+        };
+        match logs::TimeWindow::parse(spec, newest) {
+            Ok(window) => {
+                self.window = Some(window);
+                Ok(format!("Answering from {}", window))
+            }
+            Err(e) => Ok(e),
+        }
+    }
+
+    fn set_retention(&mut self, retention: logs::Retention) -> Result<String, OrtError> {
+        self.retention = retention;
+        let dropped = self.enforce_retention();
+        Ok(format!("Retention set, dropped {} records", dropped))
+    }
+}
+
+impl EducationalAgent {
+    // indexes the markdown and code snippets under root_path
+    pub fn new(root_path: &str, config: &AgentConfig) -> Result<Self, OrtError> {
+        let docs = parser::parse_snippets(root_path).map_err(|e| OrtError::new(e.to_string()))?;
+        if docs.is_empty() {
+            return Err(OrtError::new(format!("no snippets found under {}", root_path)));
+        }
+        let mut rag_base = RAGBase::new(root_path, &docs, config)?;
+        rag_base.set_prompt("educational")?;
+        Ok(Self { rag_base })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, OrtError> {
+        let mut rag_base = RAGBase::load(path)?;
+        rag_base.set_prompt("educational")?;
+        Ok(Self { rag_base })
+    }
+
+    // a language tag of the corpus the question names, e.g. "rust" in "... in Rust"
+    fn language_in(&self, question: &str) -> Option<String> {
+        let languages = self.rag_base.vec_db.meta_values("language");
+        question.split(|c: char| !(c.is_alphanumeric() || c == '+' || c == '#'))
+            .map(|w| w.to_lowercase())
+            .find(|w| languages.contains(w))
+    }
+
+    // the best snippet in full with its tags, then the runners-up by title
+    fn best_snippet(&mut self, question: &str) -> Result<String, OrtError> {
+        let retrieved = self.rag_base.retrieve(question)?;
+        let mut seen: Vec<&str> = vec![];
+        for r in &retrieved {
+            if !seen.contains(&r.chunk.doc_id.as_str()) {
+                seen.push(&r.chunk.doc_id);
+            }
+        }
+        let Some(best) = retrieved.first() else {
+            return Ok("No snippet matches".to_string());
+        };
+
+        let tags: Vec<String> = parser::SNIPPET_TAGS.iter()
+            .filter_map(|t| best.chunk.metadata.get(*t).map(|v| v.to_string()))
+            .collect();
+        let text = self.rag_base.vec_db.document_text(&best.chunk.doc_id).unwrap_or_else(|| best.chunk.text.clone());
+        let mut res = format!("[{} | {}]\n{}\n", tags.join(" | "), best.chunk.doc_id, text.trim_end());
+        if seen.len() > 1 {
+            res.push_str("\nSee also:\n");
+            for doc_id in &seen[1..] {
+                let r = retrieved.iter().find(|r| r.chunk.doc_id == *doc_id).unwrap();
+                let title = r.chunk.metadata.get("title").map_or(doc_id.to_string(), |t| t.to_string());
+                res.push_str(&format!("- {} ({})\n", title, doc_id));
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl AgentI for EducationalAgent {
+
+    // the best snippet with its explanation, limited to the language the question names
+    // unless a language filter is already set
+    fn execute(&mut self, user_input: &str) -> Result<String, OrtError> {
+        let filters = &self.rag_base.options.filters;
+        let has_language_filter = filters.iter().any(|f| matches!(f,
+            Filter::Eq(k, _) | Filter::Ne(k, _) | Filter::StartsWith(k, _) | Filter::Contains(k, _) if k == "language"));
+        let language = if has_language_filter { None } else { self.language_in(user_input) };
+
+        let n_filters = filters.len();
+        if let Some(language) = language {
+            self.rag_base.options.filters.push(Filter::Eq("language".to_string(), MetaValue::Str(language)));
+        }
+        let res = if self.rag_base.llm.is_some() || self.rag_base.qa.is_some() {
+            self.rag_base.query(user_input)
+        } else {
+            self.best_snippet(user_input)
+        };
+        self.rag_base.options.filters.truncate(n_filters);
+        res
+    }
+
+    fn save(&self, path: &Path) -> Result<(), OrtError> {
+        self.rag_base.save(path)
+    }
+
+    fn refresh(&mut self) -> Result<String, OrtError> {
+        let root = self.rag_base.vec_db.source().location.clone();
+        let docs = parser::parse_snippets(&root).map_err(|e| OrtError::new(e.to_string()))?;
+        let (n, removed) = self.rag_base.sync_documents(&docs)?;
+        Ok(format!("Re-embedded {} chunks, removed {} snippets", n, removed))
+    }
+
+    fn rag_base_mut(&mut self) -> &mut RAGBase {
+        &mut self.rag_base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn vecdb_file(version: u32) -> VecDBFile {
+        let doc = Document::new("src/lib.rs:3-5", "pub struct Task {\n    id: u64,\n}\n")
+            .with_meta("language", "rust")
+            .with_meta("start_line", 3usize);
+        let chunks = chunk_document(&doc, 20, 5).unwrap();
+        let embeddings = (0..chunks.len() * 2).map(|i| i as f32 * 0.5 - 1.0).collect();
+        VecDBFile {
+            version,
+            source: SourceInfo {
+                location: "src".to_string(),
+                chunk_size: 500,
+                overlap: 100,
+                model: "all-MiniLM-L6-v2".to_string(),
+                model_key: Some("code-embedding".to_string()),
+            },
+            index: IndexKind::Hnsw(Default::default()),
+            chunks,
+            dim: 2,
+            embeddings,
+        }
+    }
+
+    #[test]
+    fn test_vecdb_file_roundtrip() {
+        let file = vecdb_file(VECDB_FORMAT_VERSION);
+        assert!(file.chunks.len() > 1);
+        let json = serde_json::to_string(&file).unwrap();
+        let loaded: VecDBFile = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(loaded.version, VECDB_FORMAT_VERSION);
+        assert_eq!(loaded.source.model_key.as_deref(), Some("code-embedding"));
+        assert_eq!(loaded.index, file.index);
+        assert_eq!((loaded.dim, &loaded.embeddings), (2, &file.embeddings));
+        for (a, b) in loaded.chunks.iter().zip(&file.chunks) {
+            assert_eq!((&a.doc_id, &a.text, a.offset, &a.metadata), (&b.doc_id, &b.text, b.offset, &b.metadata));
+        }
+        // line numbers stay integers so Lt/Gt filters keep working
+        assert_eq!(loaded.chunks[0].metadata["start_line"], MetaValue::Int(3));
+        assert_eq!(serde_json::to_string(&loaded).unwrap(), json);
+    }
+
+    #[test]
+    fn test_vecdb_load_rejects_other_versions() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("index.json");
+        fs::write(&path, serde_json::to_string(&vecdb_file(VECDB_FORMAT_VERSION - 1)).unwrap()).unwrap();
+        // the version is checked before any model is loaded
+        let Err(e) = VecDB::load(&path) else {
+            panic!("an index of another version loaded");
+        };
+        assert!(e.to_string().contains(&format!("unsupported index version {}", VECDB_FORMAT_VERSION - 1)), "{}", e);
+    }
+
+    #[test]
+    fn test_collapse_overlapping_windows() {
+        let text = "alpha beta gamma delta epsilon zeta eta theta iota kappa lambda mu";
+        let doc = Document::new("greek", text);
+        let mut chunks = chunk_document(&doc, 25, 10).unwrap();
+        chunks.extend(chunk_document(&Document::new("other", "alpha beta gamma"), 25, 10).unwrap());
+        let other = chunks.len() - 1;
+
+        // rows 0 and 1 overlap, the last greek window does not touch row 0
+        let ranked = vec![(0.9, 1), (0.8, other), (0.7, 0), (0.6, chunks.len() - 2)];
+        let groups = group_overlapping(&chunks, &ranked);
+        assert_eq!(groups[0], (0.9, vec![1, 0]));
+        assert_eq!(groups[1], (0.8, vec![other]));
+
+        let merged = merge_chunks(&chunks, &groups[0].1);
+        assert_eq!(merged.offset, 0);
+        assert!(text.starts_with(&merged.text));
+        assert_eq!(merged.text.len(), chunks[1].offset + chunks[1].text.len());
+
+        let greek: Vec<&Chunk> = chunks.iter().filter(|c| c.doc_id == "greek").rev().collect();
+        assert_eq!(stitch_chunks(greek), text);
+    }
+
+    #[test]
+    fn test_sync_plan_embeds_only_edited_chunks() {
+        let old_docs = [
+            Document::new("a", "alpha beta gamma delta epsilon"),
+            Document::new("b", "one two three four five six seven"),
+            Document::new("c", "gone"),
+        ];
+        let old: Vec<Chunk> = old_docs.iter().flat_map(|d| chunk_document(d, 20, 0).unwrap()).collect();
+        let b_rows: Vec<usize> = (0..old.len()).filter(|&i| old[i].doc_id == "b").collect();
+        assert_eq!(b_rows.len(), 2);
+
+        let new_docs = [
+            old_docs[0].clone(),
+            Document::new("b", "one two three four FIVE six seven"),
+            Document::new("d", "new"),
+        ];
+        let new = new_docs.iter().map(|d| (d.id.as_str(), chunk_document(d, 20, 0).unwrap())).collect();
+        let plan = plan_sync(&old, new, true);
+
+        // a is untouched, c is removed, b and d are (re)inserted
+        let mut dropped: Vec<&str> = plan.drop.iter().map(|id| id.as_str()).collect();
+        dropped.sort();
+        assert_eq!(dropped, ["b", "c", "d"]);
+        assert_eq!(plan.removed, 1);
+        assert!(plan.chunks.iter().all(|c| c.doc_id != "a"));
+        // only the edited chunk of b and the new document need embedding
+        assert_eq!(plan.reuse, [Some(b_rows[0]), None, None]);
+
+        // syncing what is already stored changes nothing
+        let stored: Vec<Chunk> = new_docs.iter().flat_map(|d| chunk_document(d, 20, 0).unwrap()).collect();
+        let new = new_docs.iter().map(|d| (d.id.as_str(), chunk_document(d, 20, 0).unwrap())).collect();
+        let plan = plan_sync(&stored, new, true);
+        assert!(plan.drop.is_empty() && plan.chunks.is_empty());
+    }
+
+    #[test]
+    fn test_rerank_reorders_then_truncates() {
+        let retrieved: Vec<Retrieved> = ["a", "b", "c", "d"].iter().enumerate()
+            .map(|(i, id)| Retrieved {
+                chunk: Chunk { doc_id: id.to_string(), text: id.to_string(), offset: 0, metadata: Metadata::new() },
+                score: 1.0 - i as f32 * 0.1,
+                rerank_score: None,
+            })
+            .collect();
+        let reranked = rerank(retrieved, &[0.1, 0.9, -2.0, 0.5], 3);
+        let ids: Vec<&str> = reranked.iter().map(|r| r.chunk.doc_id.as_str()).collect();
+        assert_eq!(ids, ["b", "d", "a"]);
+        assert_eq!(reranked[0].rerank_score, Some(0.9));
+        // the first-stage score is kept
+        assert_eq!(reranked[0].score, 0.9);
+    }
+
+    #[test]
+    fn test_mock_backend_sees_prompt() {
+        let retrieved: Vec<Retrieved> = chunk_document(&Document::new("POST /tasks", "POST /tasks: create a task"), 150, 0)
+            .unwrap()
+            .into_iter()
+            .map(|chunk| Retrieved { chunk, score: 0.9, rerank_score: None })
+            .collect();
+        let chunks: Vec<&Chunk> = retrieved.iter().map(|r| &r.chunk).collect();
+        let prompt = PromptTemplate::load("openapi").unwrap().render("how do I create a task", &chunks, &[]);
+        assert!(prompt.contains("[1] POST /tasks\n"));
+        assert!(prompt.contains("[1] POST /tasks: create a task"));
+        assert!(prompt.contains("Question: how do I create a task\nAnswer:"));
+
+        let mut mock = MockBackend::default();
+        let first = mock.generate(&prompt).unwrap();
+        assert_eq!(first, mock.generate(&prompt).unwrap());
+        assert_eq!(mock.prompts.len(), 2);
+
+        let mut mock = MockBackend { reply: Some("use POST /tasks".to_string()), ..Default::default() };
+        assert_eq!(mock.generate(&prompt).unwrap(), "use POST /tasks");
+    }
+
+    #[test]
+    fn test_chunk_document_narrows_line_range() {
+        let text = (1..=20).map(|i| format!("line number {}", i)).collect::<Vec<_>>().join("\n");
+        let doc = Document::new("src/lib.rs:10-29", &text)
+            .with_meta("language", "rust")
+            .with_meta("start_line", 10usize)
+            .with_meta("end_line", 29usize);
+
+        let chunks = chunk_document(&doc, 60, 0).unwrap();
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks[0].metadata["start_line"], MetaValue::Int(10));
+        assert_eq!(chunks.last().unwrap().metadata["end_line"], MetaValue::Int(29));
+        for c in &chunks {
+            assert_eq!(c.metadata["language"], MetaValue::from("rust"));
+            let MetaValue::Int(first) = c.metadata["start_line"] else { panic!() };
+            assert!(c.text.starts_with(&format!("line number {}", first - 9)));
+        }
+    }
+
+    #[test]
+    fn test_builder_detects_and_configures() {
+        let dir = TempDir::new().unwrap();
+        let code = dir.path().join("code");
+        fs::create_dir_all(code.join("src")).unwrap();
+        fs::write(code.join("src/lib.rs"), "pub struct A;").unwrap();
+        fs::write(code.join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(code.join("README.md"), "# A").unwrap();
+        let docs = dir.path().join("docs");
+        fs::create_dir_all(&docs).unwrap();
+        fs::write(docs.join("guide.md"), "# Guide").unwrap();
+        let spec = dir.path().join("api.yaml");
+        fs::write(&spec, "openapi: 3.0.0\npaths: {}\n").unwrap();
+
+        let kind = |source: &std::path::Path| AgentBuilder::new(source.to_str().unwrap()).kind().unwrap();
+        assert_eq!(kind(&code), AgentSpecialization::Codebase);
+        assert_eq!(kind(&docs), AgentSpecialization::Docs);
+        assert_eq!(kind(&spec), AgentSpecialization::OpenAPI);
+        assert_eq!(kind(&docs.join("guide.md")), AgentSpecialization::Docs);
+        assert_eq!(AgentBuilder::new("docs/**/*.md").kind().unwrap(), AgentSpecialization::Docs);
+        assert_eq!(AgentBuilder::new("stdin,unix:/tmp/app.sock").kind().unwrap(), AgentSpecialization::Debugger);
+        fs::write(docs.join("thread.md"), "---\nlanguage: rust\ntopic: threads\n---\n# Start a thread\n").unwrap();
+        assert_eq!(kind(&docs), AgentSpecialization::Educational);
+        assert!(AgentBuilder::new("/does/not/exist.json").kind().is_err());
+
+        // a '?' in a url is a query string, not a glob
+        let versioned = dir.path().join("openapi.json?v=2");
+        fs::write(&versioned, "{\"openapi\": \"3.0.0\", \"paths\": {}}").unwrap();
+        let builder = AgentBuilder::new(&format!("file://{}", versioned.display()));
+        assert_eq!(builder.kind().unwrap(), AgentSpecialization::OpenAPI);
+        // build reuses what detection fetched
+        assert!(builder.fetched.get().is_some_and(|content| content.contains("openapi")));
+        let err = AgentBuilder::new("http://127.0.0.1:1/openapi.json?v=2").kind().unwrap_err();
+        assert!(err.to_string().contains("127.0.0.1:1/openapi.json?v=2"), "{}", err);
+
+        let builder = AgentBuilder::new("api.json")
+            .set("kind", "codebase").unwrap()
+            .set("overlap", "50").unwrap()
+            .set("mode", "hybrid:weighted:0.7").unwrap();
+        assert!(builder.clone().set("chunk_size", "big").is_err());
+        assert!(builder.clone().set("mode", "hybrid:weighted:3").unwrap_err().contains("between 0 and 1"));
+        assert!(RetrievalMode::parse("hybrid weighted -1").is_err());
+        assert_eq!(RetrievalMode::parse("hybrid weighted 1").unwrap(), RetrievalMode::Hybrid(Fusion::Weighted { semantic_weight: 1.0 }));
+        assert_eq!(builder.kind().unwrap(), AgentSpecialization::Codebase);
+        let config = builder.config(AgentSpecialization::Codebase);
+        assert_eq!((config.chunk_size, config.overlap, config.model), (500, 50, None));
+        assert_eq!(config.mode, RetrievalMode::Hybrid(Fusion::Weighted { semantic_weight: 0.7 }));
+        assert!(config.collapse_overlaps);
+        assert_eq!(config.index, IndexKind::BruteForce);
+
+        let config = builder.set("index", "hnsw:8:100:32").unwrap().config(AgentSpecialization::Codebase);
+        assert_eq!(config.index, IndexKind::parse("hnsw 8 100 32").unwrap());
+    }
+}
@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+// Okapi BM25 over an inverted index. Rows line up with VecDB chunks, same as the vector index.
+pub struct Bm25Index {
+    k1: f32,
+    b: f32,
+    // term -> (row, term frequency)
+    postings: HashMap<String, Vec<(usize, u32)>>,
+    doc_lens: Vec<usize>,
+    total_len: usize,
+}
+
+impl Bm25Index {
+    pub fn new() -> Self {
+        Self {
+            k1: 1.2,
+            b: 0.75,
+            postings: HashMap::new(),
+            doc_lens: vec![],
+            total_len: 0,
+        }
+    }
+
+    pub fn build<'a>(texts: impl Iterator<Item = &'a str>) -> Self {
+        let mut index = Self::new();
+        for text in texts {
+            index.add(text);
+        }
+        index
+    }
+
+    // indexes the next row
+    pub fn add(&mut self, text: &str) {
+        let row = self.doc_lens.len();
+        let tokens = tokenize(text);
+
+        let mut tf: HashMap<String, u32> = HashMap::new();
+        for t in &tokens {
+            *tf.entry(t.clone()).or_insert(0) += 1;
+        }
+        for (term, count) in tf {
+            self.postings.entry(term).or_default().push((row, count));
+        }
+        self.doc_lens.push(tokens.len());
+        self.total_len += tokens.len();
+    }
+
+    pub fn len(&self) -> usize {
+        self.doc_lens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.doc_lens.is_empty()
+    }
+
+    // returns up to n (score, row) pairs with a non-zero score, best first
+    pub fn search(&self, query: &str, n: usize, filter: &dyn Fn(usize) -> bool) -> Vec<(f32, usize)> {
+        if self.is_empty() {
+            return vec![];
+        }
+        let n_docs = self.doc_lens.len() as f32;
+        let avgdl = (self.total_len as f32 / n_docs).max(1.0);
+
+        let mut scores: HashMap<usize, f32> = HashMap::new();
+        let mut terms = tokenize(query);
+        terms.sort();
+        terms.dedup();
+        for term in terms {
+            let Some(postings) = self.postings.get(&term) else {
+                continue;
+            };
+            let df = postings.len() as f32;
+            let idf = ((n_docs - df + 0.5) / (df + 0.5) + 1.0).ln();
+            for &(row, tf) in postings {
+                if !filter(row) {
+                    continue;
+                }
+                let tf = tf as f32;
+                let len_norm = 1.0 - self.b + self.b * self.doc_lens[row] as f32 / avgdl;
+                *scores.entry(row).or_insert(0.0) += idf * tf * (self.k1 + 1.0) / (tf + self.k1 * len_norm);
+            }
+        }
+
+        let mut res: Vec<(f32, usize)> = scores.into_iter().map(|(row, s)| (s, row)).collect();
+        res.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal).then(a.1.cmp(&b.1)));
+        res.truncate(n);
+        res
+    }
+}
+
+impl Default for Bm25Index {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// lowercased alphanumeric words, plus the whole word when it carries punctuation
+// so that identifiers like "/v1/tasks/{id}" or "snake_case" can match exactly
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    for word in text.split_whitespace() {
+        let word = word.trim_matches(|c: char| matches!(c, ',' | '.' | ':' | ';' | '"' | '\'' | '(' | ')' | '?' | '!'));
+        if word.is_empty() {
+            continue;
+        }
+        let lower = word.to_lowercase();
+        let parts: Vec<&str> = lower.split(|c: char| !c.is_alphanumeric()).filter(|p| !p.is_empty()).collect();
+        if parts.len() != 1 || parts[0] != lower {
+            tokens.push(lower.clone());
+        }
+        tokens.extend(parts.into_iter().map(|p| p.to_string()));
+    }
+    tokens
+}
+
+// how lexical and semantic rankings are merged in hybrid mode
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Fusion {
+    // reciprocal rank fusion, score = sum 1 / (k + rank)
+    Rrf { k: f32 },
+    // min-max normalised scores, semantic * w + lexical * (1 - w)
+    Weighted { semantic_weight: f32 },
+}
+
+impl Default for Fusion {
+    fn default() -> Self {
+        Fusion::Rrf { k: 60.0 }
+    }
+}
+
+// both lists are (score, row) best first, returns the fused top n
+pub fn fuse(semantic: &[(f32, usize)], lexical: &[(f32, usize)], fusion: &Fusion, n: usize) -> Vec<(f32, usize)> {
+    let mut fused: HashMap<usize, f32> = HashMap::new();
+    match fusion {
+        Fusion::Rrf { k } => {
+            for list in [semantic, lexical] {
+                for (rank, &(_, row)) in list.iter().enumerate() {
+                    *fused.entry(row).or_insert(0.0) += 1.0 / (k + rank as f32 + 1.0);
+                }
+            }
+        }
+        Fusion::Weighted { semantic_weight } => {
+            for (list, weight) in [(semantic, *semantic_weight), (lexical, 1.0 - semantic_weight)] {
+                let max = list.iter().map(|r| r.0).fold(f32::MIN, f32::max);
+                let min = list.iter().map(|r| r.0).fold(f32::MAX, f32::min);
+                let range = (max - min).max(f32::EPSILON);
+                for &(score, row) in list {
+                    *fused.entry(row).or_insert(0.0) += weight * (score - min) / range;
+                }
+            }
+        }
+    }
+
+    let mut res: Vec<(f32, usize)> = fused.into_iter().map(|(row, s)| (s, row)).collect();
+    res.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal).then(a.1.cmp(&b.1)));
+    res.truncate(n);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_identifiers_rank_first() {
+        let index = Bm25Index::build([
+            "GET /v1/tasks: list all tasks",
+            "GET /v1/tasks/{id}: get a task by id",
+            "POST /v1/edarequest: create an EdaRequest task",
+            "DELETE /v1/users/{id}: remove a user",
+        ].into_iter());
+
+        let res = index.search("which endpoint creates edarequest", 2, &|_| true);
+        assert_eq!(res[0].1, 2);
+
+        let res = index.search("/v1/tasks/{id}", 4, &|_| true);
+        assert_eq!(res[0].1, 1);
+
+        let res = index.search("/v1/tasks/{id}", 4, &|row| row != 1);
+        assert!(res.iter().all(|r| r.1 != 1));
+        assert!(index.search("nothing matches", 4, &|_| true).is_empty());
+    }
+
+    #[test]
+    fn test_fusion() {
+        let semantic = vec![(0.9, 0), (0.8, 1), (0.1, 2)];
+        let lexical = vec![(7.0, 1), (3.0, 2)];
+
+        let rrf = fuse(&semantic, &lexical, &Fusion::default(), 3);
+        assert_eq!(rrf[0].1, 1);
+
+        let sem_only = fuse(&semantic, &lexical, &Fusion::Weighted { semantic_weight: 1.0 }, 3);
+        assert_eq!(sem_only[0].1, 0);
+        let lex_only = fuse(&semantic, &lexical, &Fusion::Weighted { semantic_weight: 0.0 }, 3);
+        assert_eq!(lex_only[0].1, 1);
+    }
+}
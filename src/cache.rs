@@ -0,0 +1,174 @@
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// override with MINI_RAG_CACHE_DIR, MINI_RAG_CACHE_MAX_MB, MINI_RAG_CACHE=off disables caching
+pub const DEFAULT_CACHE_DIR: &str = "src/data/cache";
+pub const DEFAULT_CACHE_MAX_MB: u64 = 256;
+
+// content-addressed embedding vectors, one little-endian f32 file per (model, text).
+// least recently used entries (by mtime, refreshed on hits) are evicted past max_bytes
+pub struct EmbeddingCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    // bytes on disk, counted once when opened and kept up to date by put and evict
+    size: u64,
+}
+
+impl EmbeddingCache {
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let size = entries(dir)?.iter().map(|e| e.1).sum();
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            size,
+        })
+    }
+
+    // None when disabled or the directory can't be created
+    pub fn from_env() -> Option<Self> {
+        if env::var("MINI_RAG_CACHE").is_ok_and(|v| matches!(v.as_str(), "0" | "off" | "false")) {
+            return None;
+        }
+        let dir = env::var("MINI_RAG_CACHE_DIR").unwrap_or_else(|_| DEFAULT_CACHE_DIR.to_string());
+        let max_mb = env::var("MINI_RAG_CACHE_MAX_MB").ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_CACHE_MAX_MB);
+        match Self::open(Path::new(&dir), max_mb * 1024 * 1024) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                println!("Embedding cache disabled, {}: {}", dir, e);
+                None
+            }
+        }
+    }
+
+    // `model` should capture everything that changes the vector, not just the model id
+    pub fn key(model: &str, text: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(model.as_bytes());
+        hasher.update([0]);
+        hasher.update(text.as_bytes());
+        hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<f32>> {
+        let path = self.path(key);
+        let bytes = fs::read(&path).ok()?;
+        if bytes.is_empty() || bytes.len() % 4 != 0 {
+            return None;
+        }
+        // mark as recently used, a failure only makes eviction less accurate
+        let _ = File::options().write(true).open(&path).and_then(|f| f.set_modified(SystemTime::now()));
+        Some(bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect())
+    }
+
+    pub fn put(&mut self, key: &str, vector: &[f32]) -> io::Result<()> {
+        let path = self.path(key);
+        if path.exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(path.parent().unwrap())?;
+        let bytes: Vec<u8> = vector.iter().flat_map(|x| x.to_le_bytes()).collect();
+        fs::write(&path, &bytes)?;
+        self.size += bytes.len() as u64;
+        if self.size > self.max_bytes {
+            self.evict()?;
+        }
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // drops the least recently used entries until the cache is at 90% of max_bytes,
+    // so a full cache doesn't evict on every put
+    fn evict(&mut self) -> io::Result<()> {
+        let mut entries = entries(&self.dir)?;
+        entries.sort_by_key(|e| e.2);
+        self.size = entries.iter().map(|e| e.1).sum();
+
+        let target = self.max_bytes / 10 * 9;
+        for (path, len, _) in entries {
+            if self.size <= target {
+                break;
+            }
+            fs::remove_file(&path)?;
+            self.size -= len;
+        }
+        Ok(())
+    }
+
+    // fanned out by the first byte of the key to keep directories small
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[..2]).join(format!("{}.bin", key))
+    }
+}
+
+// (path, size, mtime) of every entry
+fn entries(dir: &Path) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+    let mut res = vec![];
+    for sub in fs::read_dir(dir)? {
+        let sub = sub?;
+        if !sub.file_type()?.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(sub.path())? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_file() {
+                res.push((entry.path(), meta.len(), meta.modified()?));
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_cache_roundtrip_and_eviction() {
+        let dir = TempDir::new().unwrap();
+        // room for two 4-d vectors
+        let mut cache = EmbeddingCache::open(dir.path(), 40).unwrap();
+
+        let a = EmbeddingCache::key("minilm", "alpha");
+        assert_ne!(a, EmbeddingCache::key("mpnet", "alpha"));
+        assert_eq!(a, EmbeddingCache::key("minilm", "alpha"));
+
+        cache.put(&a, &[1.0, 2.0, 3.0, 4.0]).unwrap();
+        assert_eq!(cache.get(&a), Some(vec![1.0, 2.0, 3.0, 4.0]));
+        assert_eq!(cache.get(&EmbeddingCache::key("minilm", "beta")), None);
+
+        let b = EmbeddingCache::key("minilm", "beta");
+        let c = EmbeddingCache::key("minilm", "gamma");
+        cache.put(&b, &[0.0; 4]).unwrap();
+        // explicit mtimes rather than sleeps, filesystems differ in mtime resolution
+        let age = |path: PathBuf, secs: u64| {
+            let past = SystemTime::now() - std::time::Duration::from_secs(secs);
+            File::options().write(true).open(path).unwrap().set_modified(past).unwrap();
+        };
+        age(cache.path(&a), 7200);
+        age(cache.path(&b), 3600);
+        // the hit makes a more recent than b, so b goes first
+        cache.get(&a).unwrap();
+        cache.put(&c, &[0.0; 4]).unwrap();
+
+        assert!(cache.size() <= 36);
+        assert!(cache.get(&b).is_none());
+        assert!(cache.get(&a).is_some());
+        assert!(cache.get(&c).is_some());
+
+        // size survives a reopen
+        let reopened = EmbeddingCache::open(dir.path(), 40).unwrap();
+        assert_eq!(reopened.size(), cache.size());
+    }
+}
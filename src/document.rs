@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MetaValue {
+    Int(i64),
+    Str(String),
+}
+
+impl fmt::Display for MetaValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetaValue::Int(i) => write!(f, "{}", i),
+            MetaValue::Str(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl From<&str> for MetaValue {
+    fn from(s: &str) -> Self {
+        MetaValue::Str(s.to_string())
+    }
+}
+
+impl From<String> for MetaValue {
+    fn from(s: String) -> Self {
+        MetaValue::Str(s)
+    }
+}
+
+impl From<usize> for MetaValue {
+    fn from(i: usize) -> Self {
+        MetaValue::Int(i as i64)
+    }
+}
+
+// e.g. {"method": "POST", "path": "/tasks"} or {"file": "src/app.rs", "start_line": 12, "language": "rust"}
+pub type Metadata = BTreeMap<String, MetaValue>;
+
+// a unit of source text handed to VecDB, chunked and embedded as a whole
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub id: String,
+    pub text: String,
+    pub metadata: Metadata,
+}
+
+impl Document {
+    pub fn new(id: &str, text: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            text: text.to_string(),
+            metadata: Metadata::new(),
+        }
+    }
+
+    pub fn with_meta(mut self, key: &str, value: impl Into<MetaValue>) -> Self {
+        self.metadata.insert(key.to_string(), value.into());
+        self
+    }
+}
+
+// a predicate over chunk metadata, checked while searching
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Eq(String, MetaValue),
+    Ne(String, MetaValue),
+    StartsWith(String, String),
+    Contains(String, String),
+    Lt(String, i64),
+    Gt(String, i64),
+}
+
+impl Filter {
+    // parses "<key> <op> <value>", op is one of == != startswith contains < >
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let mut parts = expr.trim().splitn(3, char::is_whitespace);
+        let (Some(key), Some(op), Some(value)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(format!("expected '<key> <op> <value>', got '{}'", expr));
+        };
+        let key = key.to_string();
+        let value = value.trim().to_string();
+        let int = || value.parse::<i64>().map_err(|_| format!("'{}' is not an integer", value));
+
+        match op {
+            "==" => Ok(Filter::Eq(key, parse_value(&value))),
+            "!=" => Ok(Filter::Ne(key, parse_value(&value))),
+            "startswith" => Ok(Filter::StartsWith(key, value)),
+            "contains" => Ok(Filter::Contains(key, value)),
+            "<" => Ok(Filter::Lt(key, int()?)),
+            ">" => Ok(Filter::Gt(key, int()?)),
+            _ => Err(format!("unknown operator '{}'", op)),
+        }
+    }
+
+    pub fn matches(&self, metadata: &Metadata) -> bool {
+        match self {
+            Filter::Eq(k, v) => metadata.get(k) == Some(v),
+            Filter::Ne(k, v) => metadata.get(k) != Some(v),
+            Filter::StartsWith(k, prefix) => metadata.get(k).is_some_and(|v| v.to_string().starts_with(prefix.as_str())),
+            Filter::Contains(k, needle) => metadata.get(k).is_some_and(|v| v.to_string().contains(needle.as_str())),
+            Filter::Lt(k, n) => matches!(metadata.get(k), Some(MetaValue::Int(i)) if i < n),
+            Filter::Gt(k, n) => matches!(metadata.get(k), Some(MetaValue::Int(i)) if i > n),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::Eq(k, v) => write!(f, "{} == {}", k, v),
+            Filter::Ne(k, v) => write!(f, "{} != {}", k, v),
+            Filter::StartsWith(k, v) => write!(f, "{} startswith {}", k, v),
+            Filter::Contains(k, v) => write!(f, "{} contains {}", k, v),
+            Filter::Lt(k, v) => write!(f, "{} < {}", k, v),
+            Filter::Gt(k, v) => write!(f, "{} > {}", k, v),
+        }
+    }
+}
+
+fn parse_value(value: &str) -> MetaValue {
+    match value.parse::<i64>() {
+        Ok(i) => MetaValue::Int(i),
+        Err(_) => MetaValue::Str(value.to_string()),
+    }
+}
+
+pub fn matches_all(filters: &[Filter], metadata: &Metadata) -> bool {
+    filters.iter().all(|f| f.matches(metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_match_filters() {
+        let doc = Document::new("GET /tasks/{id}", "")
+            .with_meta("method", "GET")
+            .with_meta("path", "/tasks/{id}")
+            .with_meta("start_line", 40usize);
+
+        let filters = vec![
+            Filter::parse("method == GET").unwrap(),
+            Filter::parse("path startswith /tasks").unwrap(),
+            Filter::parse("start_line > 10").unwrap(),
+        ];
+        assert!(matches_all(&filters, &doc.metadata));
+        assert!(!Filter::parse("method == POST").unwrap().matches(&doc.metadata));
+        assert!(!Filter::parse("language == rust").unwrap().matches(&doc.metadata));
+        assert!(Filter::parse("method ~ GET").is_err());
+        assert!(Filter::parse("start_line < ten").is_err());
+    }
+}
@@ -2,6 +2,7 @@ use std::fs;
 use std::io::{self, BufRead, BufReader};
 use std::path::{Path, PathBuf};
 use regex::Regex;
+use crate::document::Document;
 
 #[derive(Debug, Clone)]
 pub struct SearchMatch {
@@ -22,6 +23,20 @@ pub struct ClassMatch {
     pub language: String,
 }
 
+impl ClassMatch {
+    // the definition as a VecDB document, keyed by its location
+    pub fn to_document(&self) -> Document {
+        let file = self.file_path.display().to_string();
+        let id = format!("{}:{}-{}", file, self.start_line, self.end_line);
+        Document::new(&id, &self.full_definition)
+            .with_meta("file", file)
+            .with_meta("name", self.class_name.as_str())
+            .with_meta("language", self.language.as_str())
+            .with_meta("start_line", self.start_line)
+            .with_meta("end_line", self.end_line)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SearchOptions {
     pub case_insensitive: bool,
@@ -147,6 +162,9 @@ fn search_file(file_path: &Path, regex: &Regex, results: &mut Vec<SearchMatch>)
     Ok(())
 }
 
+// languages find_class_definitions understands
+pub const CODE_EXTENSIONS: [&str; 8] = ["rs", "java", "py", "cpp", "h", "cs", "ts", "js"];
+
 // Enhanced function to find full class definitions
 pub fn find_class_definitions(
     root_dir: &Path,
@@ -154,23 +172,19 @@ pub fn find_class_definitions(
 ) -> io::Result<Vec<ClassMatch>> {
     let mut results = Vec::new();
     
-    let mut options = SearchOptions::default();
-    options.file_extensions = Some(vec![
-        "rs".to_string(),
-        "java".to_string(),
-        "py".to_string(),
-        "cpp".to_string(),
-        "h".to_string(),
-        "cs".to_string(),
-        "ts".to_string(),
-        "js".to_string(),
-    ]);
+    let options = SearchOptions {
+        file_extensions: Some(CODE_EXTENSIONS.iter().map(|e| e.to_string()).collect()),
+        ..Default::default()
+    };
     
     find_classes_recursive(root_dir, class_name, &options, &mut results, 0)?;
     
     Ok(results)
 }
 
+// build output and dependencies, skipped when walking a source tree
+pub const SKIPPED_DIRS: [&str; 5] = ["target", "node_modules", "__pycache__", "venv", "dist"];
+
 fn find_classes_recursive(
     dir: &Path,
     class_name: Option<&str>,
@@ -199,13 +213,15 @@ fn find_classes_recursive(
         }
         
         if path.is_dir() {
+            if path.file_name().and_then(|n| n.to_str()).is_some_and(|n| SKIPPED_DIRS.contains(&n)) {
+                continue;
+            }
             find_classes_recursive(&path, class_name, options, results, current_depth + 1)?;
         } else if path.is_file() {
             if let Some(extensions) = &options.file_extensions {
-                if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-                    if !extensions.iter().any(|allowed| allowed == ext) {
-                        continue;
-                    }
+                let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+                if !extensions.iter().any(|allowed| allowed == ext) {
+                    continue;
                 }
             }
             
@@ -221,7 +237,12 @@ fn extract_class_definitions(
     target_class: Option<&str>,
     results: &mut Vec<ClassMatch>,
 ) -> io::Result<()> {
-    let content = fs::read_to_string(file_path)?;
+    let content = match fs::read_to_string(file_path) {
+        Ok(content) => content,
+        // not utf-8, e.g. a generated or vendored binary with a source extension
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => return Ok(()),
+        Err(e) => return Err(e),
+    };
     let lines: Vec<&str> = content.lines().collect();
     
     let language = get_language_from_extension(file_path);
@@ -236,7 +257,7 @@ fn extract_class_definitions(
     }
 }
 
-fn get_language_from_extension(file_path: &Path) -> String {
+pub fn get_language_from_extension(file_path: &Path) -> String {
     match file_path.extension().and_then(|e| e.to_str()) {
         Some("py") => "python".to_string(),
         Some("rs") => "rust".to_string(),
@@ -601,6 +622,7 @@ pub fn print_search_results(results: &[SearchMatch]) {
 }
 
 #[cfg(test)]
+#[allow(clippy::items_after_test_module, clippy::field_reassign_with_default)]
 mod tests {
     use super::*;
     use std::fs;
@@ -618,6 +640,24 @@ mod tests {
         assert_eq!(results[1].line_number, 3);
     }
 
+    #[test]
+    fn test_class_documents() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::create_dir(temp_dir.path().join("target")).unwrap();
+        fs::write(temp_dir.path().join("target/gen.rs"), "pub struct Generated {}\n").unwrap();
+        fs::write(temp_dir.path().join("README"), "class NotCode:\n").unwrap();
+        fs::write(temp_dir.path().join("blob.rs"), [0xff, 0xfe, 0x00]).unwrap();
+        fs::write(temp_dir.path().join("lib.rs"), "use std::fs;\n\npub struct Task {\n    id: u64,\n}\n").unwrap();
+
+        let classes = find_class_definitions(temp_dir.path(), None).unwrap();
+        assert_eq!(classes.len(), 1);
+        let doc = classes[0].to_document();
+        assert!(doc.id.ends_with("lib.rs:3-5"));
+        assert!(doc.text.contains("id: u64"));
+        assert_eq!(doc.metadata["name"].to_string(), "Task");
+        assert_eq!(doc.metadata["start_line"].to_string(), "3");
+    }
+
     #[test]
     fn test_case_insensitive_search() {
         let temp_dir = TempDir::new().unwrap();
@@ -633,6 +673,7 @@ mod tests {
 }
 
 // Example usage
+#[allow(dead_code)]
 fn example() -> io::Result<()> {
     let root_dir = Path::new("./src");
     
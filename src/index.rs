@@ -0,0 +1,503 @@
+use ndarray::{ArrayView1, ArrayView2, Axis};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+
+// Nearest-neighbour search over the rows of an embedding matrix.
+// Indexes never own the vectors, the caller passes the matrix in so VecDB keeps a single copy.
+// Similarity is the dot product, higher is closer.
+pub trait VectorIndex {
+    // (re)builds the index over every row of `vectors`
+    fn build(&mut self, vectors: ArrayView2<f32>);
+
+    // indexes `row`, which must be the next row appended to `vectors` since the last build/add
+    fn add(&mut self, vectors: ArrayView2<f32>, row: usize);
+
+    // returns up to n (score, row) pairs, best first, among rows accepted by `filter`
+    fn search(&self, vectors: ArrayView2<f32>, query: ArrayView1<f32>, n: usize, filter: &dyn Fn(usize) -> bool) -> Vec<(f32, usize)>;
+
+    fn len(&self) -> usize;
+
+    // the kind and parameters the index was built with, what VecDB saves
+    fn kind(&self) -> IndexKind;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum IndexKind {
+    #[default]
+    BruteForce,
+    Hnsw(HnswConfig),
+}
+
+impl IndexKind {
+    // "brute", "hnsw" or "hnsw <m> <ef_construction> <ef_search>"
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let words: Vec<&str> = spec.split_whitespace().collect();
+        let number = |w: &str| match w.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("'{}' is not a positive number", w)),
+        };
+        match words.as_slice() {
+            ["brute"] => Ok(IndexKind::BruteForce),
+            ["hnsw"] => Ok(IndexKind::Hnsw(HnswConfig::default())),
+            ["hnsw", m, ef_construction, ef_search] => Ok(IndexKind::Hnsw(HnswConfig {
+                m: number(m)?,
+                ef_construction: number(ef_construction)?,
+                ef_search: number(ef_search)?,
+                ..Default::default()
+            })),
+            _ => Err(format!("unknown index '{}', expected brute or hnsw [<m> <ef_construction> <ef_search>]", spec)),
+        }
+    }
+}
+
+pub fn build_index(kind: &IndexKind, vectors: ArrayView2<f32>) -> Box<dyn VectorIndex> {
+    let mut index: Box<dyn VectorIndex> = match kind {
+        IndexKind::BruteForce => Box::new(BruteForceIndex::new()),
+        IndexKind::Hnsw(config) => Box::new(HnswIndex::new(config.clone())),
+    };
+    index.build(vectors);
+    index
+}
+
+fn dot(a: ArrayView1<f32>, b: ArrayView1<f32>) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+// ----- brute force -----
+
+// exact linear scan, fine for a single spec
+pub struct BruteForceIndex {
+    len: usize,
+}
+
+impl BruteForceIndex {
+    pub fn new() -> Self {
+        Self { len: 0 }
+    }
+}
+
+impl Default for BruteForceIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VectorIndex for BruteForceIndex {
+    fn build(&mut self, vectors: ArrayView2<f32>) {
+        self.len = vectors.nrows();
+    }
+
+    fn add(&mut self, _vectors: ArrayView2<f32>, row: usize) {
+        self.len = self.len.max(row + 1);
+    }
+
+    fn search(&self, vectors: ArrayView2<f32>, query: ArrayView1<f32>, n: usize, filter: &dyn Fn(usize) -> bool) -> Vec<(f32, usize)> {
+        let mut res: Vec<(f32, usize)> = vectors
+            .axis_iter(Axis(0))
+            .enumerate()
+            .filter(|(i, _)| filter(*i))
+            .map(|(i, v)| (dot(query, v), i))
+            .collect();
+        res.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+        res.truncate(n);
+        res
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn kind(&self) -> IndexKind {
+        IndexKind::BruteForce
+    }
+}
+
+// ----- HNSW -----
+
+// m: links per node on the upper layers (layer 0 gets 2*m)
+// ef_construction: candidate list size while inserting, higher = better graph, slower build
+// ef_search: candidate list size while querying, higher = better recall, slower queries
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HnswConfig {
+    pub m: usize,
+    pub ef_construction: usize,
+    pub ef_search: usize,
+    pub seed: u64,
+}
+
+impl Default for HnswConfig {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 200,
+            ef_search: 64,
+            seed: 42,
+        }
+    }
+}
+
+// Hierarchical navigable small world graph (Malkov & Yashunin).
+// links[node][layer] holds the neighbours of node on that layer.
+pub struct HnswIndex {
+    config: HnswConfig,
+    links: Vec<Vec<Vec<usize>>>,
+    entry_point: Option<usize>,
+    max_level: usize,
+    rng: XorShift,
+}
+
+// heap entry ordered by score, used as both a max-heap and (via Reverse) a min-heap
+#[derive(Clone, Copy, PartialEq)]
+struct Scored(f32, usize);
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.partial_cmp(&other.0).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl HnswIndex {
+    pub fn new(config: HnswConfig) -> Self {
+        let rng = XorShift::new(config.seed);
+        Self {
+            config,
+            links: vec![],
+            entry_point: None,
+            max_level: 0,
+            rng,
+        }
+    }
+
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 { self.config.m * 2 } else { self.config.m }
+    }
+
+    fn random_level(&mut self) -> usize {
+        let ml = 1.0 / (self.config.m.max(2) as f64).ln();
+        let r = self.rng.next_f64().max(f64::MIN_POSITIVE);
+        (-r.ln() * ml).floor() as usize
+    }
+
+    // greedy best-first search on one layer, returns up to ef candidates best first.
+    // nodes rejected by `filter` are still walked through but never returned
+    fn search_layer(
+        &self,
+        vectors: ArrayView2<f32>,
+        query: ArrayView1<f32>,
+        entry_points: &[usize],
+        ef: usize,
+        layer: usize,
+        filter: &dyn Fn(usize) -> bool,
+    ) -> Vec<Scored> {
+        let mut visited: HashSet<usize> = entry_points.iter().cloned().collect();
+        // candidates to expand, best first
+        let mut candidates: BinaryHeap<Scored> = BinaryHeap::new();
+        // current results, worst on top so it can be evicted
+        let mut results: BinaryHeap<std::cmp::Reverse<Scored>> = BinaryHeap::new();
+
+        for &ep in entry_points {
+            let s = Scored(dot(query, vectors.row(ep)), ep);
+            candidates.push(s);
+            if filter(ep) {
+                results.push(std::cmp::Reverse(s));
+            }
+        }
+
+        while let Some(current) = candidates.pop() {
+            let worst = results.peek().map(|r| r.0 .0).unwrap_or(f32::MIN);
+            if current.0 < worst && results.len() >= ef {
+                break;
+            }
+            for &neighbour in &self.links[current.1][layer] {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let score = dot(query, vectors.row(neighbour));
+                let worst = results.peek().map(|r| r.0 .0).unwrap_or(f32::MIN);
+                if results.len() < ef || score > worst {
+                    candidates.push(Scored(score, neighbour));
+                    if filter(neighbour) {
+                        results.push(std::cmp::Reverse(Scored(score, neighbour)));
+                        if results.len() > ef {
+                            results.pop();
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut res: Vec<Scored> = results.into_iter().map(|r| r.0).collect();
+        res.sort_by(|a, b| b.cmp(a));
+        res
+    }
+
+    fn insert(&mut self, vectors: ArrayView2<f32>, node: usize) {
+        let level = self.random_level();
+        self.links.push(vec![vec![]; level + 1]);
+
+        let Some(mut entry) = self.entry_point else {
+            self.entry_point = Some(node);
+            self.max_level = level;
+            return;
+        };
+
+        let query = vectors.row(node);
+
+        // descend greedily through the layers above the new node's level
+        for layer in (level + 1..=self.max_level).rev() {
+            entry = self.search_layer(vectors, query, &[entry], 1, layer, &|_| true)[0].1;
+        }
+
+        let mut entry_points = vec![entry];
+        for layer in (0..=level.min(self.max_level)).rev() {
+            let candidates = self.search_layer(vectors, query, &entry_points, self.config.ef_construction, layer, &|_| true);
+            let max_links = self.max_links(layer);
+            let neighbours: Vec<usize> = candidates.iter().take(max_links).map(|s| s.1).collect();
+
+            self.links[node][layer] = neighbours.clone();
+            for &neighbour in &neighbours {
+                self.links[neighbour][layer].push(node);
+                if self.links[neighbour][layer].len() > max_links {
+                    self.prune(vectors, neighbour, layer, max_links);
+                }
+            }
+            entry_points = candidates.iter().map(|s| s.1).collect();
+        }
+
+        if level > self.max_level {
+            self.max_level = level;
+            self.entry_point = Some(node);
+        }
+    }
+
+    // keeps only the closest max_links neighbours of node on a layer
+    fn prune(&mut self, vectors: ArrayView2<f32>, node: usize, layer: usize, max_links: usize) {
+        let base = vectors.row(node);
+        let mut scored: Vec<Scored> = self.links[node][layer]
+            .iter()
+            .map(|&n| Scored(dot(base, vectors.row(n)), n))
+            .collect();
+        scored.sort_by(|a, b| b.cmp(a));
+        scored.truncate(max_links);
+        self.links[node][layer] = scored.into_iter().map(|s| s.1).collect();
+    }
+}
+
+impl VectorIndex for HnswIndex {
+    fn build(&mut self, vectors: ArrayView2<f32>) {
+        self.links.clear();
+        self.entry_point = None;
+        self.max_level = 0;
+        self.rng = XorShift::new(self.config.seed);
+        for node in 0..vectors.nrows() {
+            self.insert(vectors, node);
+        }
+    }
+
+    fn add(&mut self, vectors: ArrayView2<f32>, row: usize) {
+        assert_eq!(row, self.links.len(), "rows must be added in order");
+        self.insert(vectors, row);
+    }
+
+    fn search(&self, vectors: ArrayView2<f32>, query: ArrayView1<f32>, n: usize, filter: &dyn Fn(usize) -> bool) -> Vec<(f32, usize)> {
+        let Some(mut entry) = self.entry_point else {
+            return vec![];
+        };
+        for layer in (1..=self.max_level).rev() {
+            entry = self.search_layer(vectors, query, &[entry], 1, layer, &|_| true)[0].1;
+        }
+        let ef = self.config.ef_search.max(n);
+        self.search_layer(vectors, query, &[entry], ef, 0, filter)
+            .into_iter()
+            .take(n)
+            .map(|s| (s.0, s.1))
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.links.len()
+    }
+
+    fn kind(&self) -> IndexKind {
+        IndexKind::Hnsw(self.config.clone())
+    }
+}
+
+// Maximal marginal relevance: greedily picks n of the candidate rows, trading relevance to the
+// query against similarity to what was already picked. lambda = 1 is pure relevance.
+// returns positions into `candidates`
+pub fn mmr_select(vectors: ArrayView2<f32>, query: ArrayView1<f32>, candidates: &[usize], n: usize, lambda: f32) -> Vec<usize> {
+    let relevance: Vec<f32> = candidates.iter().map(|&row| dot(query, vectors.row(row))).collect();
+    let mut selected: Vec<usize> = vec![];
+    let mut remaining: Vec<usize> = (0..candidates.len()).collect();
+
+    while selected.len() < n && !remaining.is_empty() {
+        let mmr = |i: usize| {
+            let redundancy = selected.iter()
+                .map(|&j| dot(vectors.row(candidates[i]), vectors.row(candidates[j])))
+                .fold(f32::MIN, f32::max);
+            let redundancy = if selected.is_empty() { 0.0 } else { redundancy };
+            lambda * relevance[i] - (1.0 - lambda) * redundancy
+        };
+        let (pos, _) = remaining.iter().enumerate()
+            .map(|(pos, &i)| (pos, mmr(i)))
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+            .unwrap();
+        selected.push(remaining.remove(pos));
+    }
+    selected
+}
+
+// small deterministic PRNG so graph construction is reproducible without extra deps
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    // uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array2;
+
+    fn random_unit_vectors(rows: usize, dim: usize, seed: u64) -> Array2<f32> {
+        let mut rng = XorShift::new(seed);
+        let mut data = Array2::<f32>::zeros((rows, dim));
+        for mut row in data.axis_iter_mut(Axis(0)) {
+            row.mapv_inplace(|_| rng.next_f64() as f32 * 2.0 - 1.0);
+            let norm = row.dot(&row).sqrt();
+            row.mapv_inplace(|x| x / norm);
+        }
+        data
+    }
+
+    #[test]
+    fn test_brute_force_is_sorted() {
+        let vectors = random_unit_vectors(50, 8, 1);
+        let index = build_index(&IndexKind::BruteForce, vectors.view());
+        let res = index.search(vectors.view(), vectors.row(3), 5, &|_| true);
+        assert_eq!(res.len(), 5);
+        assert_eq!(res[0].1, 3);
+        assert!(res.windows(2).all(|w| w[0].0 >= w[1].0));
+    }
+
+    #[test]
+    fn test_hnsw_recall_against_brute_force() {
+        let vectors = random_unit_vectors(500, 32, 7);
+        let queries = random_unit_vectors(30, 32, 99);
+        let exact = build_index(&IndexKind::BruteForce, vectors.view());
+        let approx = build_index(&IndexKind::Hnsw(HnswConfig::default()), vectors.view());
+        assert_eq!(approx.len(), 500);
+
+        let k = 10;
+        let mut hits = 0;
+        for q in queries.axis_iter(Axis(0)) {
+            let truth: HashSet<usize> = exact.search(vectors.view(), q, k, &|_| true).into_iter().map(|r| r.1).collect();
+            hits += approx.search(vectors.view(), q, k, &|_| true).into_iter().filter(|r| truth.contains(&r.1)).count();
+        }
+        let recall = hits as f32 / (queries.nrows() * k) as f32;
+        assert!(recall >= 0.9, "recall too low: {}", recall);
+    }
+
+    #[test]
+    fn test_hnsw_filtered_search() {
+        let vectors = random_unit_vectors(500, 16, 13);
+        let exact = build_index(&IndexKind::BruteForce, vectors.view());
+        let approx = build_index(&IndexKind::Hnsw(HnswConfig::default()), vectors.view());
+        let even = |i: usize| i.is_multiple_of(2);
+
+        let truth = exact.search(vectors.view(), vectors.row(1), 5, &even);
+        let res = approx.search(vectors.view(), vectors.row(1), 5, &even);
+        assert_eq!(res.len(), 5);
+        assert!(res.iter().all(|r| even(r.1)));
+        assert_eq!(res[0], truth[0]);
+    }
+
+    #[test]
+    fn test_mmr_skips_near_duplicates() {
+        let vectors = ndarray::arr2(&[
+            [1.0, 0.0, 0.0],
+            [0.99, 0.14, 0.0],
+            [0.7, 0.0, 0.71],
+        ]);
+        let query = ndarray::arr1(&[0.95, 0.1, 0.2]);
+        assert_eq!(mmr_select(vectors.view(), query.view(), &[0, 1, 2], 2, 1.0), vec![1, 0]);
+        assert_eq!(mmr_select(vectors.view(), query.view(), &[0, 1, 2], 2, 0.5), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_hnsw_incremental_add_matches_build() {
+        let vectors = random_unit_vectors(200, 16, 11);
+        let built = build_index(&IndexKind::Hnsw(HnswConfig::default()), vectors.view());
+        let mut grown = build_index(&IndexKind::Hnsw(HnswConfig::default()), vectors.slice(ndarray::s![..100, ..]));
+        for row in 100..200 {
+            grown.add(vectors.view(), row);
+        }
+        assert_eq!(grown.len(), 200);
+        for q in [0, 150, 199] {
+            assert_eq!(grown.search(vectors.view(), vectors.row(q), 3, &|_| true), built.search(vectors.view(), vectors.row(q), 3, &|_| true));
+        }
+    }
+
+    #[test]
+    fn test_hnsw_ef_search_trades_recall() {
+        let vectors = random_unit_vectors(500, 32, 3);
+        let queries = random_unit_vectors(30, 32, 5);
+        let exact = build_index(&IndexKind::BruteForce, vectors.view());
+
+        let recall_at = |ef_search: usize| {
+            let config = HnswConfig { m: 4, ef_construction: 20, ef_search, ..Default::default() };
+            let approx = build_index(&IndexKind::Hnsw(config), vectors.view());
+            let mut hits = 0;
+            for q in queries.axis_iter(Axis(0)) {
+                let truth: HashSet<usize> = exact.search(vectors.view(), q, 10, &|_| true).into_iter().map(|r| r.1).collect();
+                hits += approx.search(vectors.view(), q, 10, &|_| true).into_iter().filter(|r| truth.contains(&r.1)).count();
+            }
+            hits
+        };
+        assert!(recall_at(200) >= recall_at(10));
+    }
+
+    #[test]
+    fn test_parse_index_kind() {
+        let kind = IndexKind::parse("hnsw 8 100 32").unwrap();
+        let config = HnswConfig { m: 8, ef_construction: 100, ef_search: 32, ..Default::default() };
+        assert_eq!(kind, IndexKind::Hnsw(config));
+        // the parameters reach the built index
+        let vectors = random_unit_vectors(20, 4, 7);
+        assert_eq!(build_index(&kind, vectors.view()).kind(), kind);
+        assert_eq!(IndexKind::parse("hnsw").unwrap(), IndexKind::Hnsw(HnswConfig::default()));
+        assert_eq!(IndexKind::parse("brute").unwrap(), IndexKind::BruteForce);
+        assert!(IndexKind::parse("hnsw 0 100 32").is_err());
+        assert!(IndexKind::parse("hnsw 8").is_err());
+    }
+}
@@ -0,0 +1,581 @@
+use crate::document::{Document, Filter, MetaValue};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::LazyLock;
+use std::thread;
+
+// "2024-05-01T14:03:22.123Z", "2024/05/01 14:03:22,123 +0200", "[2024-05-01 14:03]" ...
+static TIMESTAMP: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(\d{4})[-/](\d{2})[-/](\d{2})[T ](\d{2}):(\d{2})(?::(\d{2}))?(?:[.,]\d+)?(?:\s?(Z|[+-]\d{2}:?\d{2}))?").unwrap()
+});
+static LEVEL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\b(TRACE|DEBUG|INFO|WARN|WARNING|ERROR|FATAL|CRITICAL)\b").unwrap()
+});
+// a time of day or a full timestamp in a question or window spec
+const TIME: &str = r"((?:\d{4}-\d{2}-\d{2}[T ])?\d{1,2}:\d{2}(?::\d{2})?)";
+static BETWEEN: LazyLock<Regex> = LazyLock::new(|| Regex::new(&format!(r"(?i)\bbetween {} and {}", TIME, TIME)).unwrap());
+static AROUND: LazyLock<Regex> = LazyLock::new(|| Regex::new(&format!(r"(?i)\b(?:around|about|near|at) {}", TIME)).unwrap());
+static LAST: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\b(?:last|past) (\d+) ?(s|sec|secs|seconds?|m|min|mins|minutes?|h|hours?|d|days?)\b").unwrap()
+});
+
+// timestamps are only looked for near the start of a line, later ones are usually payload
+const TIMESTAMP_PREFIX: usize = 64;
+// "around 14:00" means 14:00 give or take this many seconds
+const AROUND_SECS: i64 = 15 * 60;
+
+// one log entry: a line with a timestamp plus the lines below it that have none,
+// e.g. a stack trace
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    pub source: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    // unix seconds, UTC unless the line gave an offset. lines without one inherit the
+    // timestamp of the record before them
+    pub timestamp: Option<i64>,
+    pub level: Option<String>,
+    pub text: String,
+}
+
+impl LogRecord {
+    // keyed by source:line, the timestamp and level are kept for filtering
+    pub fn to_document(&self) -> Document {
+        let mut doc = Document::new(&format!("{}:{}", self.source, self.start_line), self.text.trim_end())
+            .with_meta("file", self.source.as_str())
+            .with_meta("start_line", self.start_line)
+            .with_meta("end_line", self.end_line);
+        if let Some(timestamp) = self.timestamp {
+            doc = doc.with_meta("timestamp", MetaValue::Int(timestamp));
+        }
+        if let Some(level) = &self.level {
+            doc = doc.with_meta("level", level.as_str());
+        }
+        doc
+    }
+}
+
+// groups the lines of one source into records
+#[derive(Debug, Clone)]
+pub struct LogParser {
+    source: String,
+    // lines seen so far
+    line: usize,
+    current: Option<LogRecord>,
+    last_timestamp: Option<i64>,
+}
+
+impl LogParser {
+    pub fn new(source: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            line: 0,
+            current: None,
+            last_timestamp: None,
+        }
+    }
+
+    // the record the line completed, if it started a new one
+    pub fn push(&mut self, line: &str) -> Option<LogRecord> {
+        self.line += 1;
+        let line = line.trim_end_matches('\r');
+        let timestamp = parse_timestamp(line);
+        if timestamp.is_none() {
+            if let Some(current) = &mut self.current {
+                current.text.push('\n');
+                current.text.push_str(line);
+                current.end_line = self.line;
+                return None;
+            }
+            if line.trim().is_empty() {
+                return None;
+            }
+        }
+
+        if timestamp.is_some() {
+            self.last_timestamp = timestamp;
+        }
+        let done = self.current.take();
+        self.current = Some(LogRecord {
+            source: self.source.clone(),
+            start_line: self.line,
+            end_line: self.line,
+            timestamp: self.last_timestamp,
+            level: parse_level(line),
+            text: line.to_string(),
+        });
+        done
+    }
+
+    // the record still collecting continuation lines. a continuation that arrives after
+    // the flush becomes a record of its own
+    pub fn flush(&mut self) -> Option<LogRecord> {
+        self.current.take()
+    }
+}
+
+// unix seconds of the first timestamp near the start of the line
+pub fn parse_timestamp(line: &str) -> Option<i64> {
+    let end = (0..=TIMESTAMP_PREFIX.min(line.len())).rev().find(|&i| line.is_char_boundary(i))?;
+    let caps = TIMESTAMP.captures(&line[..end])?;
+    let num = |i: usize| caps.get(i).map_or(Some(0), |m| m.as_str().parse::<i64>().ok());
+    let (month, day, hour, minute, second) = (num(2)?, num(3)?, num(4)?, num(5)?, num(6)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let mut timestamp = days_from_civil(num(1)?, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    if let Some(offset) = caps.get(7).map(|m| m.as_str()).filter(|o| *o != "Z") {
+        let digits: String = offset[1..].chars().filter(|c| c.is_ascii_digit()).collect();
+        let secs = digits[..2].parse::<i64>().ok()? * 3600 + digits[2..].parse::<i64>().ok()? * 60;
+        timestamp -= if offset.starts_with('-') { -secs } else { secs };
+    }
+    Some(timestamp)
+}
+
+fn parse_level(line: &str) -> Option<String> {
+    let level = LEVEL.captures(line)?.get(1)?.as_str();
+    Some(match level {
+        "WARNING" => "WARN",
+        "CRITICAL" => "FATAL",
+        level => level,
+    }.to_string())
+}
+
+// days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
+
+// "2024-05-01 14:03:22", UTC
+pub fn format_timestamp(timestamp: i64) -> String {
+    let (year, month, day) = civil_from_days(timestamp.div_euclid(86400));
+    let secs = timestamp.rem_euclid(86400);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+// "45s", "30m", "2h", "7d", or plain seconds
+pub fn parse_duration(spec: &str) -> Option<i64> {
+    let spec = spec.trim();
+    let split = spec.find(|c: char| !c.is_ascii_digit()).unwrap_or(spec.len());
+    let n: i64 = spec[..split].parse().ok()?;
+    let unit = match spec[split..].trim() {
+        "" | "s" | "sec" | "secs" | "second" | "seconds" => 1,
+        "m" | "min" | "mins" | "minute" | "minutes" => 60,
+        "h" | "hour" | "hours" => 3600,
+        "d" | "day" | "days" => 86400,
+        _ => return None,
+    };
+    Some(n * unit)
+}
+
+// an inclusive range of unix seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeWindow {
+    pub from: i64,
+    pub to: i64,
+}
+
+impl TimeWindow {
+    // "last 30m", "around 14:00", "13:50 14:10" or "2024-05-01T13:50 2024-05-01T14:10".
+    // times of day are on the day of `reference`, the newest record
+    pub fn parse(spec: &str, reference: i64) -> Result<Self, String> {
+        let words: Vec<&str> = spec.split_whitespace().collect();
+        let time = |t: &str| resolve_time(t, reference).ok_or_else(|| format!("'{}' is not a time", t));
+        match words.as_slice() {
+            ["last", duration] => parse_duration(duration)
+                .map(|secs| Self { from: reference - secs, to: reference })
+                .ok_or_else(|| format!("'{}' is not a duration", duration)),
+            ["around", t] => time(t).map(|t| Self { from: t - AROUND_SECS, to: t + AROUND_SECS }),
+            [from, to] => Ok(Self { from: time(from)?, to: time(to)? }),
+            _ => Err(format!("expected 'last <duration>', 'around <time>' or '<from> <to>', got '{}'", spec)),
+        }
+    }
+
+    // a window the question asks about, e.g. "why did the export fail around 14:00"
+    pub fn from_question(question: &str, reference: i64) -> Option<Self> {
+        if let Some(caps) = BETWEEN.captures(question) {
+            let from = resolve_time(&caps[1], reference)?;
+            let to = resolve_time(&caps[2], reference)?;
+            return Some(Self { from: from.min(to), to: from.max(to) });
+        }
+        if let Some(caps) = AROUND.captures(question) {
+            let t = resolve_time(&caps[1], reference)?;
+            return Some(Self { from: t - AROUND_SECS, to: t + AROUND_SECS });
+        }
+        let caps = LAST.captures(question)?;
+        let secs = parse_duration(&format!("{}{}", &caps[1], caps[2].to_lowercase()))?;
+        Some(Self { from: reference - secs, to: reference })
+    }
+
+    // as chunk metadata filters on "timestamp"
+    pub fn filters(&self) -> Vec<Filter> {
+        vec![
+            Filter::Gt("timestamp".to_string(), self.from - 1),
+            Filter::Lt("timestamp".to_string(), self.to + 1),
+        ]
+    }
+}
+
+impl std::fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} to {}", format_timestamp(self.from), format_timestamp(self.to))
+    }
+}
+
+// a full timestamp, or a time of day on the reference's day. a time later than the
+// reference is taken from the day before, logs don't come from the future
+fn resolve_time(t: &str, reference: i64) -> Option<i64> {
+    if t.len() > 8 {
+        return parse_timestamp(t);
+    }
+    let parts: Vec<i64> = t.split(':').map(|p| p.parse().ok()).collect::<Option<_>>()?;
+    let (hour, minute, second) = match parts.as_slice() {
+        [h, m] => (*h, *m, 0),
+        [h, m, s] => (*h, *m, *s),
+        _ => return None,
+    };
+    if hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    let t = reference.div_euclid(86400) * 86400 + hour * 3600 + minute * 60 + second;
+    Some(if t > reference + AROUND_SECS { t - 86400 } else { t })
+}
+
+// how much of a log the index keeps, oldest records go first
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Retention {
+    pub max_records: Option<usize>,
+    // seconds before the newest record
+    pub max_age: Option<i64>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_records: Some(100_000),
+            max_age: None,
+        }
+    }
+}
+
+impl Retention {
+    // the records that are kept, given in the order they arrived
+    pub fn apply(&self, records: Vec<LogRecord>, newest: Option<i64>) -> Vec<LogRecord> {
+        let mut records: Vec<LogRecord> = match (self.max_age, newest) {
+            (Some(age), Some(newest)) => records.into_iter()
+                .filter(|r| r.timestamp.is_none_or(|t| t >= newest - age))
+                .collect(),
+            _ => records,
+        };
+        if let Some(max) = self.max_records {
+            let excess = records.len().saturating_sub(max);
+            records.drain(..excess);
+        }
+        records
+    }
+}
+
+// "app.log", "app.log,worker.log", "stdin" (or "-") or "unix:/tmp/app.sock"
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogSource {
+    File(PathBuf),
+    Stdin,
+    Socket(PathBuf),
+}
+
+impl LogSource {
+    pub fn parse_list(spec: &str) -> Vec<Self> {
+        spec.split(',').map(str::trim).filter(|s| !s.is_empty()).map(|s| match s {
+            "-" | "stdin" => LogSource::Stdin,
+            s => match s.strip_prefix("unix:") {
+                Some(path) => LogSource::Socket(PathBuf::from(path)),
+                None => LogSource::File(PathBuf::from(s.strip_prefix("file://").unwrap_or(s))),
+            },
+        }).collect()
+    }
+
+    // the name records are keyed by
+    pub fn name(&self) -> String {
+        match self {
+            LogSource::File(path) => path.to_string_lossy().to_string(),
+            LogSource::Stdin => "stdin".to_string(),
+            LogSource::Socket(path) => format!("unix:{}", path.display()),
+        }
+    }
+}
+
+// true for stdin, sockets and lists of .log files
+pub fn is_log_source(spec: &str) -> bool {
+    let sources = LogSource::parse_list(spec);
+    !sources.is_empty() && sources.iter().all(|s| match s {
+        LogSource::File(path) => path.extension().is_some_and(|e| e == "log"),
+        _ => true,
+    })
+}
+
+// where reading stopped in one input, saved next to the index so a restart neither
+// misses nor re-reads lines
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputState {
+    pub name: String,
+    pub offset: u64,
+    pub line: usize,
+}
+
+enum Input {
+    // bytes after the last full line are left for the next poll
+    File { path: PathBuf, offset: u64 },
+    // lines read by a background thread
+    Stream(Receiver<String>),
+}
+
+// follows a set of log sources, each poll returns the records written since the last one
+pub struct LogTail {
+    inputs: Vec<(Input, LogParser)>,
+}
+
+impl LogTail {
+    // files are read from the start, stdin and sockets from now on
+    pub fn open(spec: &str) -> io::Result<Self> {
+        let sources = LogSource::parse_list(spec);
+        if sources.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no log sources given"));
+        }
+        let mut inputs = vec![];
+        for source in sources {
+            let input = match &source {
+                LogSource::File(path) => {
+                    File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+                    Input::File { path: path.clone(), offset: 0 }
+                }
+                LogSource::Stdin => {
+                    let (tx, rx) = mpsc::channel();
+                    thread::spawn(move || forward_lines(io::stdin().lock(), tx));
+                    Input::Stream(rx)
+                }
+                LogSource::Socket(path) => Input::Stream(listen(path)?),
+            };
+            inputs.push((input, LogParser::new(&source.name())));
+        }
+        Ok(Self { inputs })
+    }
+
+    pub fn poll(&mut self) -> io::Result<Vec<LogRecord>> {
+        let mut records = vec![];
+        for (input, parser) in &mut self.inputs {
+            match input {
+                Input::File { path, offset } => {
+                    let mut file = File::open(&*path)?;
+                    let len = file.metadata()?.len();
+                    if len < *offset {
+                        // truncated or rotated, start over
+                        println!("{} shrank, reading it from the start", path.display());
+                        *offset = 0;
+                        *parser = LogParser::new(&parser.source);
+                    }
+                    file.seek(SeekFrom::Start(*offset))?;
+                    let mut bytes = vec![];
+                    file.read_to_end(&mut bytes)?;
+                    let Some(end) = bytes.iter().rposition(|&b| b == b'\n') else {
+                        continue;
+                    };
+                    *offset += end as u64 + 1;
+                    for line in String::from_utf8_lossy(&bytes[..end]).split('\n') {
+                        records.extend(parser.push(line));
+                    }
+                }
+                Input::Stream(rx) => {
+                    for line in rx.try_iter() {
+                        records.extend(parser.push(&line));
+                    }
+                }
+            }
+            records.extend(parser.flush());
+        }
+        Ok(records)
+    }
+
+    pub fn state(&self) -> Vec<InputState> {
+        self.inputs.iter().map(|(input, parser)| InputState {
+            name: parser.source.clone(),
+            offset: match input {
+                Input::File { offset, .. } => *offset,
+                Input::Stream(_) => 0,
+            },
+            line: parser.line,
+        }).collect()
+    }
+
+    // resumes inputs from a saved state, unknown names are ignored
+    pub fn restore(&mut self, state: &[InputState]) {
+        for (input, parser) in &mut self.inputs {
+            let Some(saved) = state.iter().find(|s| s.name == parser.source) else {
+                continue;
+            };
+            if let Input::File { offset, .. } = input {
+                *offset = saved.offset;
+            }
+            parser.line = saved.line;
+        }
+    }
+}
+
+fn forward_lines(reader: impl BufRead, tx: Sender<String>) {
+    for line in reader.lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if tx.send(line).is_err() {
+            break;
+        }
+    }
+}
+
+// lines written by any client connecting to a unix socket at path
+#[cfg(unix)]
+fn listen(path: &Path) -> io::Result<Receiver<String>> {
+    use std::os::unix::net::UnixListener;
+    // left behind by a previous run
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let tx = tx.clone();
+            thread::spawn(move || forward_lines(BufReader::new(stream), tx));
+        }
+    });
+    Ok(rx)
+}
+
+#[cfg(not(unix))]
+fn listen(path: &Path) -> io::Result<Receiver<String>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("unix sockets are not available here: {}", path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_timestamps() {
+        let t = parse_timestamp("2024-05-01T14:03:22Z INFO export started").unwrap();
+        assert_eq!(format_timestamp(t), "2024-05-01 14:03:22");
+        assert_eq!(parse_timestamp("[2024/05/01 16:03:22,120 +02:00] ERROR boom"), Some(t));
+        assert_eq!(parse_timestamp("2024-05-01 14:03 worker up"), Some(t - 22));
+        assert_eq!(parse_timestamp("    at export::run (src/export.rs:40)"), None);
+        assert_eq!(parse_timestamp("2024-13-01 14:03:22 bad month"), None);
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(parse_duration("30m"), Some(1800));
+        assert_eq!(parse_duration("2 hours"), Some(7200));
+        assert_eq!(parse_duration("soon"), None);
+    }
+
+    #[test]
+    fn test_parser_groups_continuations() {
+        let mut parser = LogParser::new("app.log");
+        let lines = [
+            "2024-05-01 13:59:58 INFO export job started",
+            "2024-05-01 14:00:03 ERROR export job failed",
+            "Traceback (most recent call last):",
+            "  TimeoutError: s3 upload",
+            "2024-05-01 14:00:04 WARNING retrying",
+        ];
+        let mut records: Vec<LogRecord> = lines.iter().filter_map(|l| parser.push(l)).collect();
+        records.extend(parser.flush());
+        assert_eq!(records.len(), 3);
+        assert_eq!((records[1].start_line, records[1].end_line), (2, 4));
+        assert_eq!(records[1].level.as_deref(), Some("ERROR"));
+        assert!(records[1].text.ends_with("TimeoutError: s3 upload"));
+        assert_eq!(records[2].level.as_deref(), Some("WARN"));
+
+        let doc = records[1].to_document();
+        assert_eq!(doc.id, "app.log:2");
+        assert_eq!(doc.metadata["timestamp"], MetaValue::Int(records[1].timestamp.unwrap()));
+    }
+
+    #[test]
+    fn test_time_windows() {
+        let newest = parse_timestamp("2024-05-01 14:30:00").unwrap();
+        let at = |t: &str| parse_timestamp(&format!("2024-05-01 {}", t)).unwrap();
+
+        let around = TimeWindow::from_question("why did the export job fail around 14:00", newest).unwrap();
+        assert_eq!(around, TimeWindow { from: at("13:45:00"), to: at("14:15:00") });
+        let between = TimeWindow::from_question("errors between 14:10 and 13:50?", newest).unwrap();
+        assert_eq!(between, TimeWindow { from: at("13:50:00"), to: at("14:10:00") });
+        let last = TimeWindow::from_question("anything in the last 10 minutes", newest).unwrap();
+        assert_eq!(last, TimeWindow { from: at("14:20:00"), to: newest });
+        assert_eq!(TimeWindow::from_question("why did the export fail", newest), None);
+
+        // later than the newest record, so the day before
+        let yesterday = TimeWindow::parse("around 23:00", newest).unwrap();
+        assert_eq!(format_timestamp(yesterday.from), "2024-04-30 22:45:00");
+        assert_eq!(TimeWindow::parse("13:50 2024-05-01T14:10", newest).unwrap(), between);
+        assert!(TimeWindow::parse("last week", newest).is_err());
+    }
+
+    #[test]
+    fn test_retention_and_tail() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("app.log");
+        let mut file = File::create(&path).unwrap();
+        writeln!(file, "2024-05-01 10:00:00 INFO old").unwrap();
+        writeln!(file, "2024-05-01 14:00:00 INFO recent").unwrap();
+        write!(file, "2024-05-01 14:00:01 ERROR not finished").unwrap();
+
+        let spec = path.to_str().unwrap();
+        assert!(is_log_source(spec));
+        assert!(is_log_source(&format!("stdin,{}", spec)));
+        assert!(!is_log_source("notes.md"));
+
+        let mut tail = LogTail::open(spec).unwrap();
+        let records = tail.poll().unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(tail.poll().unwrap().is_empty());
+
+        // the partial line is picked up once it ends
+        writeln!(file, " yet").unwrap();
+        let records = tail.poll().unwrap();
+        assert_eq!(records[0].text, "2024-05-01 14:00:01 ERROR not finished yet");
+        assert_eq!(records[0].start_line, 3);
+
+        // a new tail resumes where the saved one stopped
+        let mut resumed = LogTail::open(spec).unwrap();
+        resumed.restore(&tail.state());
+        assert!(resumed.poll().unwrap().is_empty());
+
+        let newest = records[0].timestamp;
+        let mut all = LogTail::open(spec).unwrap().poll().unwrap();
+        all.push(LogRecord { timestamp: None, ..records[0].clone() });
+        let kept = Retention { max_records: None, max_age: Some(3600) }.apply(all.clone(), newest);
+        assert_eq!(kept.len(), 3);
+        assert!(kept.iter().all(|r| !r.text.contains("old")));
+        let kept = Retention { max_records: Some(1), max_age: None }.apply(all, newest);
+        assert_eq!(kept[0].timestamp, None);
+    }
+}
@@ -5,12 +5,19 @@ pub mod app;
 pub mod server;
 pub mod client;
 pub mod grep;
+pub mod index;
+pub mod document;
+pub mod bm25;
+pub mod cache;
+pub mod prompt;
+pub mod logs;
+pub mod openapi;
 // use app::AgentI;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use std::io;
 use std::net::TcpStream;
 
-#[allow(dead_code)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 enum LogLevel {
     DEBUG,
     NONE
@@ -1,85 +1,407 @@
 use ort::{
-    session::{builder::GraphOptimizationLevel, Session}, value::{Tensor, TensorValueType, Value}, Error as OrtError
+    session::{builder::GraphOptimizationLevel, Session, SessionInputValue, SessionOutputs}, value::{DynValue, Tensor, TensorValueType, Value, ValueType}, Error as OrtError
 };
-use tokenizers::{Tokenizer};
-use ndarray::{Ix2, IxDynImpl, Dim};
+use tokenizers::{Encoding, Tokenizer, TruncationParams, TruncationStrategy};
+use ndarray::{Array1, Array2, ArrayView1, ArrayView3, Axis, Ix2, Ix3, IxDynImpl, Dim};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::cache::EmbeddingCache;
+use crate::index::XorShift;
 
+// relative model paths are resolved against this, override with MINI_RAG_MODEL_DIR
+pub const DEFAULT_MODEL_DIR: &str = "src/models";
+// optional per-role overrides, override the location with MINI_RAG_MODELS
+pub const MODELS_FILE: &str = "models.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ModelRole {
+    Embedding,
+    QA,
+    Reranker,
+    Decoder,
+}
+
+impl ModelRole {
+    // key in models.json and infix of the MINI_RAG_<ROLE>_* env vars
+    pub fn key(&self) -> &'static str {
+        match self {
+            ModelRole::Embedding => "embedding",
+            ModelRole::QA => "qa",
+            ModelRole::Reranker => "reranker",
+            ModelRole::Decoder => "decoder",
+        }
+    }
+
+    fn default_config(&self) -> ModelConfig {
+        let (id, onnx, tokenizer, embedding_dim) = match self {
+            ModelRole::Embedding => ("all-MiniLM-L6-v2", "all-MiniLM-L6-v2.onnx", "minilm-tokenizer.json", Some(384)),
+            ModelRole::QA => ("onnx_roberta_qa", "onnx_roberta_qa/model.onnx", "onnx_roberta_qa/tokenizer.json", None),
+            ModelRole::Reranker => ("onnx_reranker", "onnx_reranker/model.onnx", "onnx_reranker/tokenizer.json", None),
+            ModelRole::Decoder => ("onnx_decoder", "onnx_decoder/model.onnx", "onnx_decoder/tokenizer.json", None),
+        };
+        ModelConfig {
+            id: id.to_string(),
+            onnx: PathBuf::from(onnx),
+            tokenizer: PathBuf::from(tokenizer),
+            inputs: vec![],
+            outputs: vec![],
+            embedding_dim,
+            pooling: Pooling::default(),
+            normalize: false,
+            max_tokens: default_max_tokens(),
+            truncation: Truncation::default(),
+            batch_size: default_batch_size(),
+            stride: default_stride(),
+        }
+    }
+}
+
+fn default_stride() -> usize {
+    128
+}
+
+fn default_max_tokens() -> usize {
+    512
+}
+
+fn default_batch_size() -> usize {
+    32
+}
+
+// what EmbeddingModel does with inputs longer than max_tokens
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Truncation {
+    // fail the whole batch
+    Error,
+    // keep the first max_tokens
+    #[default]
+    Head,
+    // keep the last max_tokens
+    Tail,
+    // embed overlapping windows of max_tokens, `stride` apart, and average them
+    Window { stride: usize },
+}
+
+impl Truncation {
+    // "error", "head", "tail" or "window:<stride>"
+    pub fn parse(s: &str) -> Result<Self, OrtError> {
+        match s.split_once(':') {
+            Some(("window", stride)) => stride.parse()
+                .map(|stride| Truncation::Window { stride })
+                .map_err(|_| OrtError::new(format!("invalid window stride {}", stride))),
+            None if s == "error" => Ok(Truncation::Error),
+            None if s == "head" => Ok(Truncation::Head),
+            None if s == "tail" => Ok(Truncation::Tail),
+            _ => Err(OrtError::new(format!("unknown truncation {}", s))),
+        }
+    }
+
+    // token ranges to embed for an input of `len` tokens
+    pub fn spans(&self, len: usize, max_tokens: usize) -> Result<Vec<(usize, usize)>, OrtError> {
+        if len <= max_tokens {
+            return Ok(vec![(0, len)]);
+        }
+        match *self {
+            Truncation::Error => Err(OrtError::new(format!("input has {} tokens, max is {}", len, max_tokens))),
+            Truncation::Head => Ok(vec![(0, max_tokens)]),
+            Truncation::Tail => Ok(vec![(len - max_tokens, len)]),
+            Truncation::Window { stride } => {
+                let stride = stride.clamp(1, max_tokens);
+                let mut spans = vec![];
+                let mut start = 0;
+                loop {
+                    let end = (start + max_tokens).min(len);
+                    spans.push((start, end));
+                    if end == len {
+                        break;
+                    }
+                    start += stride;
+                }
+                Ok(spans)
+            }
+        }
+    }
+}
+
+// how EmbeddingModel turns model output into one vector per input
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pooling {
+    // the model already outputs [batch, dim] sentence embeddings (e.g. a pooler_output)
+    #[default]
+    Pooled,
+    // the rest pool a token-level [batch, seq, dim] output such as last_hidden_state,
+    // ignoring padding via the attention mask
+    Mean,
+    Cls,
+    Max,
+}
+
+impl Pooling {
+    pub fn parse(s: &str) -> Result<Self, OrtError> {
+        match s {
+            "pooled" => Ok(Pooling::Pooled),
+            "mean" => Ok(Pooling::Mean),
+            "cls" => Ok(Pooling::Cls),
+            "max" => Ok(Pooling::Max),
+            _ => Err(OrtError::new(format!("unknown pooling {}", s))),
+        }
+    }
+}
+
+// Where a model lives and how to talk to it.
+// inputs/outputs name the tensors in the order the model type uses them
+// (ids, mask[, token types] / its outputs); left empty they are matched by position.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelConfig {
+    pub id: String,
+    pub onnx: PathBuf,
+    pub tokenizer: PathBuf,
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    #[serde(default)]
+    pub outputs: Vec<String>,
+    #[serde(default)]
+    pub embedding_dim: Option<usize>,
+    #[serde(default)]
+    pub pooling: Pooling,
+    // L2-normalise embeddings so VecDB's dot product is cosine similarity
+    #[serde(default)]
+    pub normalize: bool,
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: usize,
+    #[serde(default)]
+    pub truncation: Truncation,
+    // inputs per forward pass, bounds the padded batch held in memory
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    // overlap in tokens between the context windows of QA models
+    #[serde(default = "default_stride")]
+    pub stride: usize,
+}
+
+impl ModelConfig {
+    // built-in defaults, replaced by the role's entry in models.json, then patched by env vars:
+    // MINI_RAG_<ROLE>_{ID,ONNX,TOKENIZER,INPUTS,OUTPUTS,DIM,POOLING,NORMALIZE,MAX_TOKENS,TRUNCATION,BATCH_SIZE,STRIDE},
+    // lists are comma separated
+    pub fn from_env(role: ModelRole) -> Result<Self, OrtError> {
+        Self::from_key(role, role.key())
+    }
+
+    // like from_env, but reads the models.json entry under `key`, e.g. a second embedding
+    // model kept as "code-embedding". the role still picks the defaults and env vars
+    pub fn from_key(role: ModelRole, key: &str) -> Result<Self, OrtError> {
+        let model_dir = PathBuf::from(env::var("MINI_RAG_MODEL_DIR").unwrap_or_else(|_| DEFAULT_MODEL_DIR.to_string()));
+        let models_file = env::var("MINI_RAG_MODELS").map(PathBuf::from).unwrap_or_else(|_| model_dir.join(MODELS_FILE));
+        Self::layered(role, key, &model_dir, &models_file, |name| env::var(name).ok())
+    }
+
+    // the layering behind from_key, with relative paths resolved against model_dir and
+    // the MINI_RAG_<ROLE>_* overrides looked up through `lookup`
+    fn layered(
+        role: ModelRole,
+        key: &str,
+        model_dir: &Path,
+        models_file: &Path,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, OrtError> {
+        let mut config = role.default_config();
+        if models_file.exists() {
+            let content = fs::read_to_string(models_file).map_err(|e| OrtError::new(e.to_string()))?;
+            let mut file: HashMap<String, ModelConfig> = serde_json::from_str(&content)
+                .map_err(|e| OrtError::new(format!("{}: {}", models_file.display(), e)))?;
+            match file.remove(key) {
+                Some(entry) => config = entry,
+                None if key != role.key() => {
+                    return Err(OrtError::new(format!("no model {} in {}", key, models_file.display())));
+                }
+                None => {}
+            }
+        } else if key != role.key() {
+            return Err(OrtError::new(format!("no model {}, {} does not exist", key, models_file.display())));
+        }
+
+        let var = |name: &str| lookup(&format!("MINI_RAG_{}_{}", role.key().to_uppercase(), name));
+        let list = |v: String| v.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect();
+        if let Some(v) = var("ID") { config.id = v; }
+        if let Some(v) = var("ONNX") { config.onnx = PathBuf::from(v); }
+        if let Some(v) = var("TOKENIZER") { config.tokenizer = PathBuf::from(v); }
+        if let Some(v) = var("INPUTS") { config.inputs = list(v); }
+        if let Some(v) = var("OUTPUTS") { config.outputs = list(v); }
+        if let Some(v) = var("DIM") {
+            config.embedding_dim = Some(v.parse().map_err(|_| OrtError::new(format!("invalid embedding dim {}", v)))?);
+        }
+        if let Some(v) = var("POOLING") { config.pooling = Pooling::parse(&v)?; }
+        if let Some(v) = var("NORMALIZE") { config.normalize = matches!(v.as_str(), "1" | "true"); }
+        if let Some(v) = var("MAX_TOKENS") {
+            config.max_tokens = v.parse().map_err(|_| OrtError::new(format!("invalid max tokens {}", v)))?;
+        }
+        if let Some(v) = var("TRUNCATION") { config.truncation = Truncation::parse(&v)?; }
+        if let Some(v) = var("BATCH_SIZE") {
+            config.batch_size = v.parse().map_err(|_| OrtError::new(format!("invalid batch size {}", v)))?;
+        }
+        if let Some(v) = var("STRIDE") {
+            config.stride = v.parse().map_err(|_| OrtError::new(format!("invalid stride {}", v)))?;
+        }
+
+        config.onnx = resolve(model_dir, &config.onnx);
+        config.tokenizer = resolve(model_dir, &config.tokenizer);
+        Ok(config)
+    }
+}
+
+fn resolve(dir: &Path, path: &Path) -> PathBuf {
+    if path.is_absolute() { path.to_path_buf() } else { dir.join(path) }
+}
 
 pub struct ModelBase  {
     ort_session: Session,
     tokenizer: Tokenizer,
+    config: ModelConfig,
 }
 
 pub struct EmbeddingModel {
     modelbase: ModelBase,
     pub embeddings: ndarray::Array2<f32>,
+    // attention mask of the last encode, needed to pool token-level outputs
+    mask: Vec<i64>,
+    // input each encoded row came from, inputs split into windows own several rows
+    row_owner: Vec<usize>,
+    // (input index, token count) of inputs over max_tokens in the last encode
+    pub truncated: Vec<(usize, usize)>,
+    cache: Option<EmbeddingCache>,
 }
 
 pub struct BertModel {
     modelbase: ModelBase,
-    input_ids: Vec<i64>,
+    // question + context window encodings of the last encode, map tokens back to the context
+    windows: Vec<Encoding>,
+}
+
+// cross-encoder that scores (query, passage) pairs jointly, used to rerank retrieved chunks
+pub struct RerankerModel {
+    modelbase: ModelBase,
+    // BERT-style cross-encoders also take segment ids, kept from encode for forward
+    type_ids: Vec<i64>,
+    shape: [usize; 2],
 }
 
+// decoder-only language model exported with past key values (e.g. optimum's gpt2 export),
+// generates one token per forward pass, reusing the attention cache of the previous ones
+pub struct DecoderModel {
+    modelbase: ModelBase,
+    // (past_key_values.* input name, shape, data), filled from the matching present.* outputs
+    past: Vec<(String, Vec<i64>, Vec<f32>)>,
+    // tokens in the cache, the position of the next one
+    seen: usize,
+}
+
+pub type ModelInputs = (Value<TensorValueType<i64>>, Value<TensorValueType<i64>>);
+
 pub trait ModelI <'a> where Self: Sized {
     type EncodeInput;
     type OutputTensor;
-    fn new() -> Result<Self, OrtError> ;
+    fn new(config: &ModelConfig) -> Result<Self, OrtError> ;
     fn forward(&mut self, ids: Value<TensorValueType<i64>>, mask: Value<TensorValueType<i64>>) -> Result<Self::OutputTensor, OrtError>;
-    fn encode(&mut self, input: Self::EncodeInput) -> Result<(Value<TensorValueType<i64>>, Value<TensorValueType<i64>>), OrtError>;    
+    fn encode(&mut self, input: Self::EncodeInput) -> Result<ModelInputs, OrtError>;    
 }
 
 
 impl ModelBase {
-    fn new(onnx_path: &str, tok_path: &str) -> Result<Self, OrtError> {
+    fn new(config: &ModelConfig) -> Result<Self, OrtError> {
         let sess = Session::builder()?
             .with_optimization_level(GraphOptimizationLevel::Level3)?
             // .with_intra_threads(1)?
-            .commit_from_file(onnx_path)?;
+            .commit_from_file(&config.onnx)?;
 
-        let tokenizer = Tokenizer::from_file(tok_path).unwrap();
+        let tokenizer = Tokenizer::from_file(&config.tokenizer)
+            .map_err(|e| OrtError::new(format!("{}: {}", config.tokenizer.display(), e)))?;
         
         Ok(Self {
             ort_session: sess,
-            tokenizer  : tokenizer
+            tokenizer,
+            config: config.clone(),
         })
     }
 }
 
+// runs the session, binding inputs by the configured names or else by position
+fn run_session<'s>(session: &'s mut Session, names: &[String], values: Vec<Value<TensorValueType<i64>>>) -> Result<SessionOutputs<'s>, OrtError> {
+    if names.is_empty() {
+        let values: Vec<SessionInputValue> = values.into_iter().map(SessionInputValue::from).collect();
+        return session.run(&values[..]);
+    }
+    if names.len() < values.len() {
+        return Err(OrtError::new(format!("expected {} input names, got {:?}", values.len(), names)));
+    }
+    let named: Vec<(Cow<str>, SessionInputValue)> = names.iter()
+        .zip(values)
+        .map(|(n, v)| (Cow::from(n.as_str()), SessionInputValue::from(v)))
+        .collect();
+    session.run(named)
+}
+
+// the slot-th output the model type expects, by configured name or else at `default_index`
+fn select_output<'o>(outputs: &'o SessionOutputs, names: &[String], slot: usize, default_index: usize) -> Result<&'o DynValue, OrtError> {
+    match names.get(slot) {
+        Some(name) => outputs.get(name).ok_or_else(|| OrtError::new(format!("model has no output named {}", name))),
+        None => Ok(&outputs[default_index]),
+    }
+}
+
 
 impl<'a> ModelI<'a> for EmbeddingModel {
     type EncodeInput = &'a Vec<String>;
     type OutputTensor = ndarray::Array2<f32>;
 
-    fn new() -> Result<Self, OrtError> {
-        let modelbase = ModelBase::new(
-            "src/models/all-MiniLM-L6-v2.onnx", 
-            "src/models/minilm-tokenizer.json"
-            )?;
+    fn new(config: &ModelConfig) -> Result<Self, OrtError> {
+        let modelbase = ModelBase::new(config)?;
 
-        let embeddings = ndarray::Array2::zeros((0, 0));
+        let embeddings = ndarray::Array2::zeros((0, config.embedding_dim.unwrap_or(0)));
 
         Ok(Self {
             modelbase,
-            embeddings
+            embeddings,
+            mask: vec![],
+            row_owner: vec![],
+            truncated: vec![],
+            cache: EmbeddingCache::from_env(),
         })
     }
 
-    fn encode(&mut self, input: Self::EncodeInput) -> Result<(Value<TensorValueType<i64>>, Value<TensorValueType<i64>>), OrtError> {
+    fn encode(&mut self, input: Self::EncodeInput) -> Result<ModelInputs, OrtError> {
         let encodings = self.modelbase.tokenizer.encode_batch(input.clone(), false)
         .map_err(|e| OrtError::new(e.to_string()))?;
 
-        let max_len = encodings.iter().map(|e| e.len()).max().unwrap();
-        if max_len > 512 {
-            return Err(OrtError::new("max_len must be < 512"));
+        let config = &self.modelbase.config;
+        self.truncated.clear();
+        self.row_owner.clear();
+        let mut rows: Vec<(&[u32], &[u32])> = vec![];
+        for (i, encoding) in encodings.iter().enumerate() {
+            let spans = config.truncation.spans(encoding.len(), config.max_tokens)?;
+            if encoding.len() > config.max_tokens {
+                self.truncated.push((i, encoding.len()));
+            }
+            for (start, end) in spans {
+                rows.push((&encoding.get_ids()[start..end], &encoding.get_attention_mask()[start..end]));
+                self.row_owner.push(i);
+            }
         }
+        let max_len = rows.iter().map(|r| r.0.len()).max().unwrap();
 
         let mut ids: Vec<i64> = Vec::new();
         let mut mask: Vec<i64> = Vec::new();
 
         // flattening token IDs and masks, while resizing
-        for encoding in &encodings {
-            let mut cur_ids :  Vec<i64> = encoding.get_ids().iter().map(|&id| id as i64).collect();
-            let mut cur_mask:  Vec<i64> = encoding.get_attention_mask().iter().map(|&m| m as i64).collect();
+        for (row_ids, row_mask) in rows.iter() {
+            let mut cur_ids :  Vec<i64> = row_ids.iter().map(|&id| id as i64).collect();
+            let mut cur_mask:  Vec<i64> = row_mask.iter().map(|&m| m as i64).collect();
 
             cur_ids.resize(max_len, 0);
             cur_mask.resize(max_len, 0);
@@ -90,8 +412,9 @@ impl<'a> ModelI<'a> for EmbeddingModel {
 
         // convert above into 2d tensors
         let padded_token_length = max_len;
-        let shape = [input.len(), padded_token_length];
+        let shape = [rows.len(), padded_token_length];
 
+        self.mask = mask.clone();
         let a_ids   = Tensor::from_array((shape, ids))?;
         let a_mask  = Tensor::from_array((shape, mask))?;
         Ok((a_ids, a_mask))
@@ -101,60 +424,287 @@ impl<'a> ModelI<'a> for EmbeddingModel {
                 ids: Value<TensorValueType<i64>>, 
                 mask: Value<TensorValueType<i64>>) -> Result<Self::OutputTensor, OrtError> {
         // run model
-        let outputs = self.modelbase.ort_session.run(ort::inputs![ids, mask])?;
+        let ModelBase { ort_session, config, .. } = &mut self.modelbase;
+        let outputs = run_session(ort_session, &config.inputs, vec![ids, mask])?;
+
+        // get embeddings tensor (2d array), the pooled output of the pyke export by default,
+        // token-level models usually put last_hidden_state first
+        let default_index = if config.pooling == Pooling::Pooled { 1 } else { 0 };
+        let output = select_output(&outputs, &config.outputs, 0, default_index)?.try_extract_array::<f32>()?;
+        let mut embeddings = match config.pooling {
+            Pooling::Pooled => output.into_dimensionality::<Ix2>()
+                                     .map_err(|e| OrtError::new(e.to_string()))?
+                                     .into_owned(),
+            pooling => {
+                let hidden = output.into_dimensionality::<Ix3>()
+                                   .map_err(|e| OrtError::new(format!("{} pooling needs a [batch, seq, dim] output: {}", config.id, e)))?;
+                pool(hidden, &self.mask, pooling)
+            }
+        };
+        // windows of one input collapse back into a single vector
+        if self.row_owner.last().is_some_and(|&o| o + 1 < self.row_owner.len()) {
+            embeddings = average_rows(&embeddings, &self.row_owner);
+        }
+        if config.normalize {
+            l2_normalize(&mut embeddings);
+        }
+
+        if let Some(dim) = config.embedding_dim {
+            if embeddings.ncols() != dim {
+                return Err(OrtError::new(format!("model {} produced {}-d embeddings, expected {}", config.id, embeddings.ncols(), dim)));
+            }
+        }
+        Ok(embeddings)
+    }
+}
 
-        // get embeddings tensor (2d array)
-        let embeddings = outputs[1].try_extract_array::<f32>()?
-                                   .into_dimensionality::<Ix2>().unwrap();
+// pools [batch, seq, dim] token states into [batch, dim], mask is the flattened [batch, seq] attention mask
+pub fn pool(hidden: ArrayView3<f32>, mask: &[i64], pooling: Pooling) -> Array2<f32> {
+    let (batch, seq, dim) = hidden.dim();
+    let mut res = Array2::<f32>::zeros((batch, dim));
+    for (b, mut out) in res.axis_iter_mut(Axis(0)).enumerate() {
+        let tokens = hidden.index_axis(Axis(0), b);
+        let live = |t: usize| mask.get(b * seq + t).is_none_or(|&m| m != 0);
+        match pooling {
+            Pooling::Pooled | Pooling::Cls => out.assign(&tokens.row(0)),
+            Pooling::Mean => {
+                let mut count = 0.0;
+                for t in (0..seq).filter(|&t| live(t)) {
+                    out += &tokens.row(t);
+                    count += 1.0;
+                }
+                out /= f32::max(count, 1.0);
+            }
+            Pooling::Max => {
+                out.fill(f32::MIN);
+                for t in (0..seq).filter(|&t| live(t)) {
+                    out.zip_mut_with(&tokens.row(t), |o, &x| *o = o.max(x));
+                }
+            }
+        }
+    }
+    res
+}
 
-        Ok(embeddings.into_owned())
+// averages the rows that belong to the same input, owners are ascending
+fn average_rows(embeddings: &Array2<f32>, owners: &[usize]) -> Array2<f32> {
+    let n = owners.last().map_or(0, |&o| o + 1);
+    let mut res = Array2::<f32>::zeros((n, embeddings.ncols()));
+    let mut counts = vec![0.0f32; n];
+    for (row, &owner) in embeddings.axis_iter(Axis(0)).zip(owners) {
+        let mut out = res.row_mut(owner);
+        out += &row;
+        counts[owner] += 1.0;
+    }
+    for (mut out, count) in res.axis_iter_mut(Axis(0)).zip(counts) {
+        out /= count.max(1.0);
+    }
+    res
+}
+
+pub fn l2_normalize(embeddings: &mut Array2<f32>) {
+    for mut row in embeddings.axis_iter_mut(Axis(0)) {
+        let norm = row.dot(&row).sqrt();
+        if norm > 0.0 {
+            row /= norm;
+        }
     }
 }
 
 impl EmbeddingModel {
-    pub fn set_embeddings(&mut self, chunks: &Vec<String>) {
-        let (a_ids, a_mask) = self.encode(chunks).unwrap();
-        let embeddings = self.forward(a_ids, a_mask).unwrap();
-        self.embeddings = embeddings;
+    pub fn set_embeddings(&mut self, chunks: &[String]) -> Result<(), OrtError> {
+        self.embeddings = self.embed(chunks)?;
+        Ok(())
+    }
+
+    pub fn model_id(&self) -> &str {
+        &self.modelbase.config.id
+    }
+
+    pub fn config(&self) -> &ModelConfig {
+        &self.modelbase.config
+    }
+
+    // embeds chunks without touching the stored matrix, used for incremental updates.
+    // reuses cached vectors, the rest runs in micro-batches of similar length and reports to EMBED_PROGRESS
+    pub fn embed(&mut self, chunks: &[String]) -> Result<Array2<f32>, OrtError> {
+        self.embed_cached(chunks, Some(&EMBED_PROGRESS))
+    }
+
+    pub fn embed_query(&mut self, query: &str) -> Result<Array1<f32>, OrtError> {
+        let embeddings = self.embed_cached(&[query.to_string()], None)?;
+        Ok(embeddings.row(0).to_owned())
+    }
+
+    fn embed_cached(&mut self, chunks: &[String], progress: Option<&EmbedProgress>) -> Result<Array2<f32>, OrtError> {
+        if let Some(progress) = progress {
+            progress.start(chunks.len());
+        }
+        let keys: Vec<String> = match self.cache {
+            Some(_) => {
+                let fingerprint = self.cache_fingerprint();
+                chunks.iter().map(|c| EmbeddingCache::key(&fingerprint, c)).collect()
+            }
+            None => vec![],
+        };
+        let hits: Vec<Option<Vec<f32>>> = match &self.cache {
+            Some(cache) => keys.iter().map(|k| cache.get(k)).collect(),
+            None => vec![None; chunks.len()],
+        };
+        let misses: Vec<usize> = (0..chunks.len()).filter(|&i| hits[i].is_none()).collect();
+        if let Some(progress) = progress {
+            progress.advance(chunks.len() - misses.len());
+        }
+
+        let texts: Vec<String> = misses.iter().map(|&i| chunks[i].clone()).collect();
+        let fresh = self.embed_batches(&texts, progress)?;
+
+        let dim = match hits.iter().flatten().next() {
+            Some(hit) if misses.is_empty() => hit.len(),
+            _ => fresh.ncols(),
+        };
+        let mut res = Array2::<f32>::zeros((chunks.len(), dim));
+        for (i, hit) in hits.iter().enumerate() {
+            if let Some(hit) = hit {
+                if hit.len() != dim {
+                    return Err(OrtError::new(format!("cached embedding has {} dims, expected {}", hit.len(), dim)));
+                }
+                res.row_mut(i).assign(&ArrayView1::from(hit.as_slice()));
+            }
+        }
+        for (row, &i) in fresh.axis_iter(Axis(0)).zip(&misses) {
+            res.row_mut(i).assign(&row);
+            if let Some(cache) = &mut self.cache {
+                if let Err(e) = cache.put(&keys[i], &row.to_vec()) {
+                    println!("Embedding cache write failed, disabling it: {}", e);
+                    self.cache = None;
+                }
+            }
+        }
+        for t in self.truncated.iter_mut() {
+            t.0 = misses[t.0];
+        }
+        Ok(res)
+    }
+
+    fn embed_batches(&mut self, chunks: &[String], progress: Option<&EmbedProgress>) -> Result<Array2<f32>, OrtError> {
+        let mut dim = self.modelbase.config.embedding_dim.unwrap_or(self.embeddings.ncols());
+        let mut res = Array2::<f32>::zeros((chunks.len(), dim));
+        let mut truncated = vec![];
+
+        for batch in length_batches(chunks, self.modelbase.config.batch_size) {
+            let texts: Vec<String> = batch.iter().map(|&i| chunks[i].clone()).collect();
+            let (a_ids, a_mask) = self.encode(&texts)?;
+            let embeddings = self.forward(a_ids, a_mask)?;
+            if embeddings.ncols() != dim {
+                // dim unknown up front, nothing has been written yet
+                dim = embeddings.ncols();
+                res = Array2::zeros((chunks.len(), dim));
+            }
+            for (row, &i) in embeddings.axis_iter(Axis(0)).zip(&batch) {
+                res.row_mut(i).assign(&row);
+            }
+            truncated.extend(self.truncated.iter().map(|&(j, tokens)| (batch[j], tokens)));
+            if let Some(progress) = progress {
+                progress.advance(batch.len());
+            }
+        }
+        truncated.sort();
+        self.truncated = truncated;
+        Ok(res)
+    }
+
+    // everything besides the text that changes a vector
+    fn cache_fingerprint(&self) -> String {
+        let c = &self.modelbase.config;
+        format!("{}|{:?}|{}|{}|{:?}", c.id, c.pooling, c.normalize, c.max_tokens, c.truncation)
+    }
+}
+
+// indices of texts grouped into batches of at most batch_size, longest first,
+// so each batch pads to a similar length
+fn length_batches(texts: &[String], batch_size: usize) -> Vec<Vec<usize>> {
+    let mut order: Vec<usize> = (0..texts.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(texts[i].len()));
+    order.chunks(batch_size.max(1)).map(|c| c.to_vec()).collect()
+}
+
+// chunks embedded so far by the current (or last) EmbeddingModel::embed call
+pub struct EmbedProgress {
+    done: AtomicUsize,
+    total: AtomicUsize,
+}
+
+pub static EMBED_PROGRESS: EmbedProgress = EmbedProgress {
+    done: AtomicUsize::new(0),
+    total: AtomicUsize::new(0),
+};
+
+impl EmbedProgress {
+    fn start(&self, total: usize) {
+        self.done.store(0, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    fn advance(&self, n: usize) {
+        self.done.fetch_add(n, Ordering::Relaxed);
+    }
+
+    // (done, total)
+    pub fn get(&self) -> (usize, usize) {
+        (self.done.load(Ordering::Relaxed), self.total.load(Ordering::Relaxed))
     }
 }
 
 
 type BertLogits = ndarray::Array<f32, Dim<IxDynImpl>>;
 impl <'a> ModelI <'a> for BertModel {
-    type EncodeInput = String;
+    // (question, context)
+    type EncodeInput = (&'a str, &'a str);
     type OutputTensor = (BertLogits, BertLogits);
-    // let model = "onnx_roberta_qa";
-    fn new() -> Result<Self, OrtError> {
-        let model = "onnx_roberta_qa";
-        // let model = "onnx_distilbert_qa";
-        let modelbase = ModelBase::new(
-            &format!("src/models/{}/model.onnx", model),
-            &format!("src/models/{}/tokenizer.json", model)
-            )?;
-        let input_ids = vec![];
+    // defaults to onnx_roberta_qa, onnx_distilbert_qa also works
+    fn new(config: &ModelConfig) -> Result<Self, OrtError> {
+        let mut modelbase = ModelBase::new(config)?;
+        // the question is kept whole, the context is split into windows overlapping by `stride` tokens
+        modelbase.tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: config.max_tokens,
+                stride: config.stride,
+                strategy: TruncationStrategy::OnlySecond,
+                ..Default::default()
+            }))
+            .map_err(|e| OrtError::new(e.to_string()))?;
 
         Ok (Self {
             modelbase,
-            input_ids,
+            windows: vec![],
         })
         
     } 
 
-    fn encode(&mut self, input: Self::EncodeInput) -> Result<(Value<TensorValueType<i64>>, 
-                                                          Value<TensorValueType<i64>>), 
-                                                          OrtError> {
-        let encoding = self.modelbase.tokenizer.encode(input, true)?;
+    fn encode(&mut self, input: Self::EncodeInput) -> Result<ModelInputs, OrtError> {
+        let (question, context) = input;
+        let mut encoding = self.modelbase.tokenizer.encode((question, context), true)?;
+        let overflowing = encoding.take_overflowing();
+        self.windows = std::iter::once(encoding).chain(overflowing).collect();
 
-        // Required inputs for BERT-style QA:
-        let input_ids: Vec<i64> = encoding.get_ids().to_vec().iter().map(|&e| e as i64).collect();
-        self.input_ids = input_ids.clone(); // TODO: try not to clone?
-        
-        let attention_mask: Vec<i64> = encoding.get_attention_mask().to_vec().iter().map(|&e| e as i64).collect();
+        // Required inputs for BERT-style QA, one row per window:
+        let max_len = self.windows.iter().map(|w| w.len()).max().unwrap_or(0);
+        let mut input_ids: Vec<i64> = Vec::new();
+        let mut attention_mask: Vec<i64> = Vec::new();
+        for window in &self.windows {
+            let mut cur_ids: Vec<i64> = window.get_ids().iter().map(|&e| e as i64).collect();
+            let mut cur_mask: Vec<i64> = window.get_attention_mask().iter().map(|&e| e as i64).collect();
+            cur_ids.resize(max_len, 0);
+            cur_mask.resize(max_len, 0);
+            input_ids.extend(cur_ids);
+            attention_mask.extend(cur_mask);
+        }
         
         // 4. Convert to ONNX tensors
-        let a_ids =  Tensor::from_array(([1, input_ids.len()], input_ids))?; 
-        let a_mask =  Tensor::from_array(([1, attention_mask.len()], attention_mask))?;
+        let shape = [self.windows.len(), max_len];
+        let a_ids =  Tensor::from_array((shape, input_ids))?; 
+        let a_mask =  Tensor::from_array((shape, attention_mask))?;
         
         Ok((a_ids, a_mask))
     }
@@ -162,28 +712,504 @@ impl <'a> ModelI <'a> for BertModel {
     fn forward(&mut self, ids: Value<TensorValueType<i64>>, 
                           mask: Value<TensorValueType<i64>>) 
                 -> Result<Self::OutputTensor, OrtError> {
-        let outputs = self.modelbase.ort_session.run(ort::inputs![ids, mask])?;
-        let start_logits  = outputs[0].try_extract_array::<f32>()?.into_owned();
-        let end_logits  = outputs[1].try_extract_array::<f32>()?.into_owned();
+        let ModelBase { ort_session, config, .. } = &mut self.modelbase;
+        let outputs = run_session(ort_session, &config.inputs, vec![ids, mask])?;
+        let start_logits  = select_output(&outputs, &config.outputs, 0, 0)?.try_extract_array::<f32>()?.into_owned();
+        let end_logits  = select_output(&outputs, &config.outputs, 1, 1)?.try_extract_array::<f32>()?.into_owned();
 
         Ok((start_logits, end_logits))        
     }
 }
 
+// an answer span, start..end are byte offsets into the context it was taken from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answer {
+    pub text: String,
+    pub score: f32,
+    pub start: usize,
+    pub end: usize,
+}
+
+// a candidate span over token positions, end inclusive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub score: f32,
+}
+
 impl BertModel {
-    pub fn decode(&self, start_logits: BertLogits, 
-                     end_logits: BertLogits) -> Result<String, OrtError> {
-        let start = start_logits.index_axis(ndarray::Axis(0), 0).iter().cloned()
-                                .enumerate().max_by(|a, b| a.1.partial_cmp(&b.1).unwrap()).unwrap().0;
-        let end   = end_logits.index_axis(ndarray::Axis(0), 0).iter().cloned()
-                                .enumerate().max_by(|a, b| a.1.partial_cmp(&b.1).unwrap()).unwrap().0;
-        println!("s{} e{}", start, end);
-        let ids = &self.input_ids[start..=end];
-        let ids_u32: Vec<u32> = ids.iter().map(|&e| e as u32).collect();
-        let answer = self.modelbase.tokenizer.decode(&ids_u32, true)?;
+    // the n most likely answers to `question` found in `context`, spans are at most max_answer_len tokens.
+    // long contexts are scored window by window and the spans merged by their position in the context
+    pub fn answer(&mut self, question: &str, context: &str, n: usize, max_answer_len: usize) -> Result<Vec<Answer>, OrtError> {
+        let (ids, mask) = self.encode((question, context))?;
+        let (start_logits, end_logits) = self.forward(ids, mask)?;
+        let as_rows = |logits: BertLogits| logits.into_dimensionality::<Ix2>().map_err(|e| OrtError::new(e.to_string()));
+        let (start_logits, end_logits) = (as_rows(start_logits)?, as_rows(end_logits)?);
+
+        let mut answers = vec![];
+        for (w, window) in self.windows.iter().enumerate() {
+            let in_context: Vec<bool> = window.get_sequence_ids().iter().map(|&s| s == Some(1)).collect();
+            let start_logits: Vec<f32> = start_logits.row(w).iter().take(window.len()).cloned().collect();
+            let end_logits: Vec<f32> = end_logits.row(w).iter().take(window.len()).cloned().collect();
+
+            let offsets = window.get_offsets();
+            answers.extend(best_spans(&start_logits, &end_logits, &in_context, max_answer_len, n).into_iter()
+                .map(|span| {
+                    let (start, end) = (offsets[span.start].0, offsets[span.end].1);
+                    Answer { text: context[start..end].trim().to_string(), score: span.score, start, end }
+                })
+                .filter(|a| !a.text.is_empty()));
+        }
+        Ok(merge_answers(answers, n))
+    }
+}
+
+// n best spans with start <= end, both inside the context and at most max_len tokens long.
+// scores are p(start) * p(end), each a softmax over the context tokens only
+pub fn best_spans(start_logits: &[f32], end_logits: &[f32], in_context: &[bool], max_len: usize, n: usize) -> Vec<Span> {
+    let p_start = context_softmax(start_logits, in_context);
+    let p_end = context_softmax(end_logits, in_context);
+
+    let mut spans = vec![];
+    for start in (0..in_context.len()).filter(|&i| in_context[i]) {
+        for end in (start..in_context.len().min(start + max_len)).take_while(|&i| in_context[i]) {
+            spans.push(Span { start, end, score: p_start[start] * p_end[end] });
+        }
+    }
+    spans.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    spans.truncate(n);
+    spans
+}
+
+// the n best answers, a span found by several overlapping windows keeps its best score
+pub fn merge_answers(mut answers: Vec<Answer>, n: usize) -> Vec<Answer> {
+    answers.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    let mut seen = std::collections::HashSet::new();
+    answers.retain(|a| seen.insert((a.start, a.end)));
+    answers.truncate(n);
+    answers
+}
+
+// softmax over the positions where `mask` is set, 0 elsewhere
+fn context_softmax(logits: &[f32], mask: &[bool]) -> Vec<f32> {
+    let max = logits.iter().zip(mask).filter(|(_, &m)| m).map(|(&l, _)| l).fold(f32::MIN, f32::max);
+    let exp: Vec<f32> = logits.iter().zip(mask).map(|(&l, &m)| if m { (l - max).exp() } else { 0.0 }).collect();
+    let sum: f32 = exp.iter().sum();
+    exp.into_iter().map(|e| if sum > 0.0 { e / sum } else { 0.0 }).collect()
+}
+
+
+impl<'a> ModelI<'a> for RerankerModel {
+    type EncodeInput = (&'a str, &'a Vec<String>);
+    type OutputTensor = Vec<f32>;
+
+    fn new(config: &ModelConfig) -> Result<Self, OrtError> {
+        let mut modelbase = ModelBase::new(config)?;
+        modelbase.tokenizer
+            .with_truncation(Some(TruncationParams { max_length: config.max_tokens, ..Default::default() }))
+            .map_err(|e| OrtError::new(e.to_string()))?;
+
+        Ok(Self {
+            modelbase,
+            type_ids: vec![],
+            shape: [0, 0],
+        })
+    }
+
+    fn encode(&mut self, input: Self::EncodeInput) -> Result<ModelInputs, OrtError> {
+        let (query, passages) = input;
+        let pairs: Vec<(String, String)> = passages.iter().map(|p| (query.to_string(), p.clone())).collect();
+        let encodings = self.modelbase.tokenizer.encode_batch(pairs, true)
+            .map_err(|e| OrtError::new(e.to_string()))?;
+
+        let max_len = encodings.iter().map(|e| e.len()).max().unwrap_or(0);
+        let mut ids: Vec<i64> = Vec::new();
+        let mut mask: Vec<i64> = Vec::new();
+        let mut type_ids: Vec<i64> = Vec::new();
+
+        for encoding in &encodings {
+            let mut cur_ids:   Vec<i64> = encoding.get_ids().iter().map(|&id| id as i64).collect();
+            let mut cur_mask:  Vec<i64> = encoding.get_attention_mask().iter().map(|&m| m as i64).collect();
+            let mut cur_types: Vec<i64> = encoding.get_type_ids().iter().map(|&t| t as i64).collect();
+
+            cur_ids.resize(max_len, 0);
+            cur_mask.resize(max_len, 0);
+            cur_types.resize(max_len, 0);
+
+            ids.extend(cur_ids);
+            mask.extend(cur_mask);
+            type_ids.extend(cur_types);
+        }
+
+        self.shape = [passages.len(), max_len];
+        self.type_ids = type_ids;
+        let a_ids   = Tensor::from_array((self.shape, ids))?;
+        let a_mask  = Tensor::from_array((self.shape, mask))?;
+        Ok((a_ids, a_mask))
+    }
+
+    fn forward(&mut self,
+                ids: Value<TensorValueType<i64>>,
+                mask: Value<TensorValueType<i64>>) -> Result<Self::OutputTensor, OrtError> {
+        let ModelBase { ort_session, config, .. } = &mut self.modelbase;
+        let mut values = vec![ids, mask];
+        if ort_session.inputs.len() > 2 {
+            values.push(Tensor::from_array((self.shape, self.type_ids.clone()))?);
+        }
+        let outputs = run_session(ort_session, &config.inputs, values)?;
+
+        // logits are [batch, 1] (or [batch, n_labels], the first label is relevance)
+        let logits = select_output(&outputs, &config.outputs, 0, 0)?.try_extract_array::<f32>()?;
+        let scores = logits.outer_iter().map(|row| row.iter().next().cloned().unwrap_or(0.0)).collect();
+        Ok(scores)
+    }
+}
+
+impl RerankerModel {
+    // relevance of each passage to the query, higher is better
+    pub fn score(&mut self, query: &str, passages: &Vec<String>) -> Result<Vec<f32>, OrtError> {
+        if passages.is_empty() {
+            return Ok(vec![]);
+        }
+        let (ids, mask) = self.encode((query, passages))?;
+        self.forward(ids, mask)
+    }
+}
+
+// how the next token is picked from the logits
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sampling {
+    Greedy,
+    // sample among the k most likely tokens
+    TopK { k: usize, temperature: f32 },
+    // sample among the most likely tokens whose probabilities add up to p
+    TopP { p: f32, temperature: f32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GenerateConfig {
+    pub max_new_tokens: usize,
+    pub sampling: Sampling,
+    // generation ends before the first of these, they are not part of the output
+    pub stop: Vec<String>,
+    pub seed: u64,
+}
+
+impl Default for GenerateConfig {
+    fn default() -> Self {
+        Self {
+            max_new_tokens: 128,
+            sampling: Sampling::Greedy,
+            stop: vec![],
+            seed: 42,
+        }
+    }
+}
+
+impl<'a> ModelI<'a> for DecoderModel {
+    // the prompt, encoding it starts a new sequence
+    type EncodeInput = &'a str;
+    // logits of the last position
+    type OutputTensor = Vec<f32>;
 
-        Ok(answer)
+    fn new(config: &ModelConfig) -> Result<Self, OrtError> {
+        let modelbase = ModelBase::new(config)?;
+        Ok(Self {
+            modelbase,
+            past: vec![],
+            seen: 0,
+        })
+    }
+
+    fn encode(&mut self, input: Self::EncodeInput) -> Result<ModelInputs, OrtError> {
+        let encoding = self.modelbase.tokenizer.encode(input, true)?;
+        let ids: Vec<i64> = encoding.get_ids().iter().map(|&id| id as i64).collect();
+        self.past.clear();
+        self.seen = 0;
+
+        let mask = vec![1i64; ids.len()];
+        let a_ids = Tensor::from_array(([1, ids.len()], ids))?;
+        let a_mask = Tensor::from_array(([1, mask.len()], mask))?;
+        Ok((a_ids, a_mask))
+    }
+
+    // ids are the tokens not yet in the cache, mask covers the cache as well
+    fn forward(&mut self, ids: Value<TensorValueType<i64>>, mask: Value<TensorValueType<i64>>) -> Result<Self::OutputTensor, OrtError> {
+        let n_new = ids.extract_tensor().0[1] as usize;
+        let ModelBase { ort_session, config, .. } = &mut self.modelbase;
+        let name = |slot: usize, default: &'static str| config.inputs.get(slot).cloned().unwrap_or(default.to_string());
+
+        let mut inputs: Vec<(Cow<str>, SessionInputValue)> = vec![
+            (Cow::from(name(0, "input_ids")), SessionInputValue::from(ids)),
+            (Cow::from(name(1, "attention_mask")), SessionInputValue::from(mask)),
+        ];
+        for input in &ort_session.inputs {
+            if input.name == "position_ids" {
+                let positions: Vec<i64> = (self.seen..self.seen + n_new).map(|p| p as i64).collect();
+                inputs.push((Cow::from("position_ids"), SessionInputValue::from(Tensor::from_array(([1, n_new], positions))?)));
+            } else if input.name == "use_cache_branch" {
+                // merged exports switch between the first pass and the cached ones
+                inputs.push((Cow::from("use_cache_branch"), SessionInputValue::from(Tensor::from_array(([1], vec![self.seen > 0]))?)));
+            } else if input.name.starts_with("past_key_values") {
+                let value = match self.past.iter().find(|p| p.0 == input.name) {
+                    Some((_, shape, data)) => Tensor::from_array((shape.clone(), data.clone()))?,
+                    None => {
+                        // first pass: an empty cache, batch 1 and 0 past tokens in the dynamic dims
+                        let ValueType::Tensor { shape, .. } = &input.input_type else {
+                            return Err(OrtError::new(format!("{} is not a tensor", input.name)));
+                        };
+                        let shape: Vec<i64> = shape.iter().enumerate()
+                            .map(|(i, &d)| if d >= 0 { d } else if i == 0 { 1 } else { 0 })
+                            .collect();
+                        Tensor::from_array((shape, Vec::<f32>::new()))?
+                    }
+                };
+                inputs.push((Cow::from(input.name.clone()), SessionInputValue::from(value)));
+            }
+        }
+
+        let outputs = ort_session.run(inputs)?;
+        let logits = match config.outputs.first() {
+            Some(name) => outputs.get(name).ok_or_else(|| OrtError::new(format!("model has no output named {}", name)))?,
+            None => outputs.get("logits").unwrap_or(&outputs[0]),
+        };
+        // [1, n_new, vocab], only the last position predicts the next token
+        let (shape, data) = logits.try_extract_tensor::<f32>()?;
+        let vocab = shape[shape.len() - 1] as usize;
+        let last = data[data.len() - vocab..].to_vec();
+
+        self.past.clear();
+        for (name, value) in outputs.iter() {
+            if let Some(rest) = name.strip_prefix("present") {
+                let (shape, data) = value.try_extract_tensor::<f32>()?;
+                self.past.push((format!("past_key_values{}", rest), shape.to_vec(), data.to_vec()));
+            }
+        }
+        self.seen += n_new;
+        Ok(last)
     }
 }
 
+impl DecoderModel {
+    // continues `prompt`, returns only the generated text
+    pub fn generate(&mut self, prompt: &str, config: &GenerateConfig) -> Result<String, OrtError> {
+        let (ids, mask) = self.encode(prompt)?;
+        let context = self.modelbase.config.max_tokens;
+        if ids.extract_tensor().0[1] as usize + config.max_new_tokens > context {
+            return Err(OrtError::new(format!("prompt too long for a {} token context", context)));
+        }
+        let mut logits = self.forward(ids, mask)?;
+
+        let eos = self.modelbase.tokenizer.token_to_id("<|endoftext|>")
+            .or_else(|| self.modelbase.tokenizer.token_to_id("</s>"));
+        let mut rng = XorShift::new(config.seed);
+        let mut tokens: Vec<u32> = vec![];
+        let mut text = String::new();
+        for _ in 0..config.max_new_tokens {
+            let next = sample_next(&logits, &config.sampling, &mut rng) as u32;
+            if Some(next) == eos {
+                break;
+            }
+            tokens.push(next);
+            text = self.modelbase.tokenizer.decode(&tokens, true)?;
+            if let Some(cut) = find_stop(&text, &config.stop) {
+                text.truncate(cut);
+                break;
+            }
+
+            let ids = Tensor::from_array(([1, 1], vec![next as i64]))?;
+            let mask = Tensor::from_array(([1, self.seen + 1], vec![1i64; self.seen + 1]))?;
+            logits = self.forward(ids, mask)?;
+        }
+        Ok(text.trim().to_string())
+    }
+}
+
+// index of the next token
+pub fn sample_next(logits: &[f32], sampling: &Sampling, rng: &mut XorShift) -> usize {
+    let argmax = || logits.iter().enumerate()
+        .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .map_or(0, |(i, _)| i);
+    let (keep, temperature): (&dyn Fn(usize, f32) -> bool, f32) = match sampling {
+        Sampling::Greedy => return argmax(),
+        Sampling::TopK { k, temperature } => (&|rank, _| rank < (*k).max(1), *temperature),
+        // the token that crosses p is kept, so there is always at least one
+        Sampling::TopP { p, temperature } => (&|_, mass_before| mass_before < *p, *temperature),
+    };
+    if temperature <= 0.0 {
+        return argmax();
+    }
+
+    // softmax at the temperature, most likely first
+    let max = logits.iter().cloned().fold(f32::MIN, f32::max);
+    let mut probs: Vec<(usize, f32)> = logits.iter().map(|&l| ((l - max) / temperature).exp()).enumerate().collect();
+    let sum: f32 = probs.iter().map(|p| p.1).sum();
+    probs.iter_mut().for_each(|p| p.1 /= sum);
+    probs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut mass = 0.0;
+    let mut candidates = vec![];
+    for (rank, &(token, p)) in probs.iter().enumerate() {
+        if !keep(rank, mass) {
+            break;
+        }
+        candidates.push((token, p));
+        mass += p;
+    }
+
+    let mut target = rng.next_f64() as f32 * mass;
+    for &(token, p) in &candidates {
+        if target < p {
+            return token;
+        }
+        target -= p;
+    }
+    candidates.last().map_or_else(argmax, |c| c.0)
+}
 
+// byte offset of the earliest stop sequence in text
+pub fn find_stop(text: &str, stop: &[String]) -> Option<usize> {
+    stop.iter().filter(|s| !s.is_empty()).filter_map(|s| text.find(s.as_str())).min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_pooling_respects_mask() {
+        // batch of 2, 3 tokens, dim 2; the second input has one padding token
+        let hidden = ndarray::arr3(&[
+            [[1.0, 2.0], [3.0, 4.0], [5.0, -6.0]],
+            [[0.0, 1.0], [2.0, 3.0], [100.0, 100.0]],
+        ]);
+        let mask = [1, 1, 1, 1, 1, 0];
+
+        let mean = pool(hidden.view(), &mask, Pooling::Mean);
+        assert_eq!(mean, ndarray::arr2(&[[3.0, 0.0], [1.0, 2.0]]));
+        let cls = pool(hidden.view(), &mask, Pooling::Cls);
+        assert_eq!(cls, ndarray::arr2(&[[1.0, 2.0], [0.0, 1.0]]));
+        let max = pool(hidden.view(), &mask, Pooling::Max);
+        assert_eq!(max, ndarray::arr2(&[[5.0, 4.0], [2.0, 3.0]]));
+
+        let mut normalized = max.clone();
+        l2_normalize(&mut normalized);
+        for row in normalized.axis_iter(Axis(0)) {
+            assert!((row.dot(&row) - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_best_spans() {
+        // [CLS] q q [SEP] c c c c [SEP]
+        let in_context = [false, false, false, false, true, true, true, true, false];
+        // argmax start (6) is after argmax end (5), and the question holds the biggest logits
+        let start = [0.0, 9.0, 0.0, 0.0, 1.0, 2.0, 3.0, 0.0, 0.0];
+        let end = [0.0, 9.0, 0.0, 0.0, 0.0, 3.0, 0.0, 2.5, 9.0];
+
+        let spans = best_spans(&start, &end, &in_context, 10, 3);
+        assert_eq!((spans[0].start, spans[0].end), (6, 7));
+        assert!(spans.iter().all(|s| s.start <= s.end && in_context[s.start] && in_context[s.end]));
+        assert!(spans.windows(2).all(|w| w[0].score >= w[1].score));
+
+        // a max length of one token only allows start == end
+        let spans = best_spans(&start, &end, &in_context, 1, 10);
+        assert_eq!(spans.len(), 4);
+        assert!(spans.iter().all(|s| s.start == s.end));
+
+        let all: f32 = best_spans(&start, &end, &in_context, 10, 100).iter().map(|s| s.score).sum();
+        assert!(all <= 1.0 + 1e-6);
+    }
+
+    #[test]
+    fn test_sampling() {
+        let logits = [1.0, 4.0, 3.0, -2.0];
+        let mut rng = XorShift::new(7);
+        assert_eq!(sample_next(&logits, &Sampling::Greedy, &mut rng), 1);
+        assert_eq!(sample_next(&logits, &Sampling::TopK { k: 1, temperature: 1.0 }, &mut rng), 1);
+        assert_eq!(sample_next(&logits, &Sampling::TopP { p: 0.01, temperature: 1.0 }, &mut rng), 1);
+
+        // only the two best tokens can come out of top-2, and both do
+        let picks: Vec<usize> = (0..200)
+            .map(|_| sample_next(&logits, &Sampling::TopK { k: 2, temperature: 1.0 }, &mut rng))
+            .collect();
+        assert!(picks.iter().all(|&t| t == 1 || t == 2));
+        assert!(picks.contains(&1) && picks.contains(&2));
+
+        // same seed, same tokens
+        let sample = |seed| {
+            let mut rng = XorShift::new(seed);
+            (0..20).map(|_| sample_next(&logits, &Sampling::TopP { p: 0.9, temperature: 1.5 }, &mut rng)).collect::<Vec<_>>()
+        };
+        assert_eq!(sample(3), sample(3));
+
+        let stop = vec!["\n\n".to_string(), "Question:".to_string()];
+        assert_eq!(find_stop("POST /tasks\n\nQuestion: next", &stop), Some(11));
+        assert_eq!(find_stop("POST /tasks", &stop), None);
+    }
+
+    #[test]
+    fn test_merge_answers() {
+        let answer = |start, end, score| Answer { text: String::new(), score, start, end };
+        // the same span seen by two windows, plus a unique one from each
+        let merged = merge_answers(vec![answer(10, 14, 0.4), answer(0, 3, 0.2), answer(10, 14, 0.7), answer(30, 35, 0.5)], 2);
+        assert_eq!(merged, vec![answer(10, 14, 0.7), answer(30, 35, 0.5)]);
+    }
+
+    #[test]
+    fn test_length_batches() {
+        let texts: Vec<String> = ["aa", "a", "aaaa", "aaa", "aaaaa"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(length_batches(&texts, 2), vec![vec![4, 2], vec![3, 0], vec![1]]);
+        assert_eq!(length_batches(&texts, 0).len(), 5);
+        assert!(length_batches(&[], 8).is_empty());
+    }
+
+    #[test]
+    fn test_truncation_spans() {
+        assert_eq!(Truncation::Error.spans(10, 16).unwrap(), vec![(0, 10)]);
+        assert!(Truncation::Error.spans(20, 16).is_err());
+        assert_eq!(Truncation::Head.spans(20, 16).unwrap(), vec![(0, 16)]);
+        assert_eq!(Truncation::Tail.spans(20, 16).unwrap(), vec![(4, 20)]);
+        assert_eq!(
+            Truncation::parse("window:8").unwrap().spans(30, 16).unwrap(),
+            vec![(0, 16), (8, 24), (16, 30)]
+        );
+
+        let rows = ndarray::arr2(&[[1.0, 1.0], [3.0, 5.0], [2.0, 2.0]]);
+        assert_eq!(average_rows(&rows, &[0, 0, 1]), ndarray::arr2(&[[2.0, 3.0], [2.0, 2.0]]));
+    }
+
+    #[test]
+    fn test_model_config_layers() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join(MODELS_FILE), r#"{
+            "qa": {"id": "distilbert", "onnx": "onnx_distilbert_qa/model.onnx", "tokenizer": "/abs/tokenizer.json",
+                   "inputs": ["input_ids", "attention_mask"]},
+            "code-embedding": {"id": "codebert", "onnx": "codebert.onnx", "tokenizer": "codebert.json", "pooling": "mean"}
+        }"#).unwrap();
+        let models_file = dir.path().join(MODELS_FILE);
+        // the process environment is shared with the other tests, so the overrides come from a map
+        let vars = HashMap::from([("MINI_RAG_EMBEDDING_DIM", "768")]);
+        let load = |role: ModelRole, key: &str| {
+            ModelConfig::layered(role, key, dir.path(), &models_file, |name| vars.get(name).map(|v| v.to_string()))
+        };
+
+        let qa = load(ModelRole::QA, "qa").unwrap();
+        assert_eq!(qa.id, "distilbert");
+        assert_eq!(qa.onnx, dir.path().join("onnx_distilbert_qa/model.onnx"));
+        assert_eq!(qa.tokenizer, PathBuf::from("/abs/tokenizer.json"));
+        assert_eq!(qa.inputs, vec!["input_ids", "attention_mask"]);
+
+        let embedding = load(ModelRole::Embedding, "embedding").unwrap();
+        assert_eq!(embedding.id, "all-MiniLM-L6-v2");
+        assert_eq!(embedding.embedding_dim, Some(768));
+        assert_eq!(embedding.tokenizer, dir.path().join("minilm-tokenizer.json"));
+
+        let code = load(ModelRole::Embedding, "code-embedding").unwrap();
+        assert_eq!((code.id.as_str(), code.pooling, code.embedding_dim), ("codebert", Pooling::Mean, Some(768)));
+        assert!(load(ModelRole::Embedding, "missing").is_err());
+    }
+}
@@ -0,0 +1,1048 @@
+use crate::document::Document;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+// nested schemas are flattened into dotted field paths down to this depth
+const MAX_FIELD_DEPTH: usize = 5;
+const METHODS: [&str; 8] = ["get", "put", "post", "delete", "options", "head", "patch", "trace"];
+
+// an OpenAPI 3.x document, only what the index uses
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Spec {
+    pub openapi: String,
+    pub info: Info,
+    pub paths: BTreeMap<String, PathItem>,
+    pub components: Components,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Info {
+    pub title: String,
+    pub version: String,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Components {
+    pub schemas: BTreeMap<String, RefOr<Schema>>,
+    pub parameters: BTreeMap<String, RefOr<Parameter>>,
+    pub request_bodies: BTreeMap<String, RefOr<RequestBody>>,
+    pub responses: BTreeMap<String, RefOr<Response>>,
+}
+
+// an inline object or a "$ref": "#/components/<kind>/<name>" pointing at one
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum RefOr<T> {
+    Ref {
+        #[serde(rename = "$ref")]
+        reference: String,
+    },
+    Item(T),
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PathItem {
+    pub summary: Option<String>,
+    pub description: Option<String>,
+    // shared by every operation of the path, operations can override them
+    pub parameters: Vec<RefOr<Parameter>>,
+    // get, post, ... kept by method name
+    #[serde(flatten)]
+    pub operations: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Operation {
+    pub operation_id: Option<String>,
+    pub summary: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    pub deprecated: bool,
+    pub parameters: Vec<RefOr<Parameter>>,
+    pub request_body: Option<RefOr<RequestBody>>,
+    pub responses: BTreeMap<String, RefOr<Response>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Parameter {
+    pub name: String,
+    #[serde(rename = "in")]
+    pub location: String,
+    pub description: Option<String>,
+    pub required: bool,
+    pub schema: Option<RefOr<Schema>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RequestBody {
+    pub description: Option<String>,
+    pub required: bool,
+    pub content: BTreeMap<String, MediaType>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Response {
+    pub description: Option<String>,
+    pub content: BTreeMap<String, MediaType>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MediaType {
+    pub schema: Option<RefOr<Schema>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Schema {
+    #[serde(rename = "type")]
+    pub schema_type: Option<Value>,
+    pub format: Option<String>,
+    pub description: Option<String>,
+    pub properties: BTreeMap<String, RefOr<Schema>>,
+    pub required: Vec<String>,
+    pub items: Option<Box<RefOr<Schema>>>,
+    #[serde(rename = "enum")]
+    pub enum_values: Vec<Value>,
+    pub all_of: Vec<RefOr<Schema>>,
+    pub one_of: Vec<RefOr<Schema>>,
+    pub any_of: Vec<RefOr<Schema>>,
+}
+
+// things a $ref can point at, and where they live under components
+pub trait Component: Sized {
+    const KIND: &'static str;
+    // the stored key and the object under it
+    fn lookup<'a>(components: &'a Components, name: &str) -> Option<(&'a String, &'a RefOr<Self>)>;
+}
+
+impl Component for Schema {
+    const KIND: &'static str = "schemas";
+    fn lookup<'a>(components: &'a Components, name: &str) -> Option<(&'a String, &'a RefOr<Self>)> {
+        components.schemas.get_key_value(name)
+    }
+}
+
+impl Component for Parameter {
+    const KIND: &'static str = "parameters";
+    fn lookup<'a>(components: &'a Components, name: &str) -> Option<(&'a String, &'a RefOr<Self>)> {
+        components.parameters.get_key_value(name)
+    }
+}
+
+impl Component for RequestBody {
+    const KIND: &'static str = "requestBodies";
+    fn lookup<'a>(components: &'a Components, name: &str) -> Option<(&'a String, &'a RefOr<Self>)> {
+        components.request_bodies.get_key_value(name)
+    }
+}
+
+impl Component for Response {
+    const KIND: &'static str = "responses";
+    fn lookup<'a>(components: &'a Components, name: &str) -> Option<(&'a String, &'a RefOr<Self>)> {
+        components.responses.get_key_value(name)
+    }
+}
+
+// one schema property, nested ones as "owner.id" and array items as "tags[]"
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub path: String,
+    pub type_name: String,
+    pub required: bool,
+    pub description: Option<String>,
+    pub enum_values: Vec<String>,
+}
+
+impl Spec {
+    // JSON when the content starts with '{', YAML otherwise
+    pub fn parse(content: &str) -> Result<Self, String> {
+        if content.trim_start().starts_with('{') {
+            Self::from_json(content)
+        } else {
+            Self::from_yaml(content)
+        }
+    }
+
+    pub fn from_json(content: &str) -> Result<Self, String> {
+        let value: Value = serde_json::from_str(content).map_err(|e| format!("invalid JSON: {}", e))?;
+        Self::from_value(value)
+    }
+
+    pub fn from_yaml(content: &str) -> Result<Self, String> {
+        let value: serde_yaml::Value = serde_yaml::from_str(content).map_err(|e| format!("invalid YAML: {}", e))?;
+        Self::from_value(yaml_to_json(value))
+    }
+
+    // OpenAPI 3.x as is, Swagger 2.0 converted to the same model first
+    pub fn from_value(mut value: Value) -> Result<Self, String> {
+        if !value.is_object() {
+            return Err("expected a JSON or YAML object at the top level".to_string());
+        }
+        if let Some(swagger) = value.get("swagger") {
+            if version_string(swagger).as_deref() != Some("2.0") {
+                return Err(format!("unsupported Swagger version {}, only 2.0 is", swagger));
+            }
+            return Self::from_value(swagger_to_openapi(value));
+        }
+        let version = match value.get("openapi") {
+            Some(version) => match version_string(version) {
+                Some(version) => version,
+                None => return Err(format!("openapi should be a version string, got {}", version)),
+            },
+            None => return Err("not an OpenAPI document, it has no openapi or swagger version".to_string()),
+        };
+        // an unquoted `openapi: 3.0` in YAML is a number
+        value["openapi"] = Value::String(version.clone());
+        if !version.starts_with('3') {
+            return Err(format!("expected an OpenAPI 3.x document, got openapi '{}'", version));
+        }
+        serde_json::from_value(value).map_err(|e| format!("malformed OpenAPI {} document: {}", version, e))
+    }
+
+    // follows $refs to the object they name, with the name of the last component on
+    // the way. a $ref that comes back to itself is an error rather than a hang
+    pub fn resolve<'a, T: Component>(&'a self, item: &'a RefOr<T>) -> Result<(&'a T, Option<&'a str>), String> {
+        let mut seen: Vec<&str> = vec![];
+        let mut current = item;
+        let mut name = None;
+        loop {
+            match current {
+                RefOr::Item(item) => return Ok((item, name)),
+                RefOr::Ref { reference } => {
+                    if seen.contains(&reference.as_str()) {
+                        return Err(format!("cyclic $ref {}", reference));
+                    }
+                    seen.push(reference);
+                    let prefix = format!("#/components/{}/", T::KIND);
+                    let Some(key) = reference.strip_prefix(&prefix) else {
+                        return Err(format!("unsupported $ref {}", reference));
+                    };
+                    let key = key.replace("~1", "/").replace("~0", "~");
+                    let (key, target) = T::lookup(&self.components, &key)
+                        .ok_or_else(|| format!("unresolved $ref {}", reference))?;
+                    name = Some(key.as_str());
+                    current = target;
+                }
+            }
+        }
+    }
+
+    // (method, path, operation) in path order, then method order
+    pub fn operations(&self) -> Vec<(String, &str, Operation)> {
+        let mut res = vec![];
+        for (path, item) in &self.paths {
+            for method in METHODS {
+                let Some(value) = item.operations.get(method) else {
+                    continue;
+                };
+                match serde_json::from_value::<Operation>(value.clone()) {
+                    Ok(op) => res.push((method.to_uppercase(), path.as_str(), op)),
+                    Err(e) => println!("Skipping {} {}: {}", method.to_uppercase(), path, e),
+                }
+            }
+        }
+        res
+    }
+
+    // path parameters overridden by the operation's own, matched on name and location
+    pub fn parameters<'a>(&'a self, item: &'a PathItem, op: &'a Operation) -> Vec<&'a Parameter> {
+        let mut params: Vec<&Parameter> = vec![];
+        for p in op.parameters.iter().chain(&item.parameters) {
+            match self.resolve(p) {
+                Ok((p, _)) => {
+                    if !params.iter().any(|q| q.name == p.name && q.location == p.location) {
+                        params.push(p);
+                    }
+                }
+                Err(e) => println!("Skipping parameter: {}", e),
+            }
+        }
+        params
+    }
+
+    // "Task", "string(date-time)", "Task[]", "integer | string"
+    pub fn type_name(&self, schema: &RefOr<Schema>) -> String {
+        let (schema, name) = match self.resolve(schema) {
+            Ok(resolved) => resolved,
+            Err(_) => {
+                let RefOr::Ref { reference } = schema else { unreachable!() };
+                return reference.rsplit('/').next().unwrap_or(reference).to_string();
+            }
+        };
+        if let Some(name) = name {
+            return name.to_string();
+        }
+        let types: Vec<&str> = match &schema.schema_type {
+            Some(Value::String(t)) => vec![t.as_str()],
+            // 3.1 allows a list, e.g. ["string", "null"]
+            Some(Value::Array(ts)) => ts.iter().filter_map(|t| t.as_str()).filter(|t| *t != "null").collect(),
+            _ => vec![],
+        };
+        if types == ["array"] {
+            return match &schema.items {
+                Some(items) => format!("{}[]", self.type_name(items)),
+                None => "array".to_string(),
+            };
+        }
+        let variants = if schema.one_of.is_empty() { &schema.any_of } else { &schema.one_of };
+        if types.is_empty() && !variants.is_empty() {
+            return variants.iter().map(|v| self.type_name(v)).collect::<Vec<_>>().join(" | ");
+        }
+        let base = if types.is_empty() {
+            if schema.properties.is_empty() && schema.all_of.is_empty() { "any".to_string() } else { "object".to_string() }
+        } else {
+            types.join(" | ")
+        };
+        match &schema.format {
+            Some(format) => format!("{}({})", base, format),
+            None => base,
+        }
+    }
+
+    // every property reachable from a schema as a flat list, each named component is
+    // expanded at most once per branch so recursive schemas stop
+    pub fn fields(&self, schema: &RefOr<Schema>) -> Vec<Field> {
+        let mut fields = vec![];
+        self.collect_fields(schema, "", &mut vec![], &mut fields);
+        fields
+    }
+
+    fn collect_fields<'a>(&'a self, schema: &'a RefOr<Schema>, prefix: &str, stack: &mut Vec<&'a str>, out: &mut Vec<Field>) {
+        let Ok((schema, name)) = self.resolve(schema) else {
+            return;
+        };
+        if let Some(name) = name {
+            if stack.contains(&name) {
+                return;
+            }
+        }
+        if prefix.matches(['.', '[']).count() >= MAX_FIELD_DEPTH {
+            return;
+        }
+        stack.extend(name);
+
+        for part in schema.all_of.iter().chain(&schema.one_of).chain(&schema.any_of) {
+            self.collect_fields(part, prefix, stack, out);
+        }
+        for (key, property) in &schema.properties {
+            let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+            let resolved = self.resolve(property).ok().map(|(p, _)| p);
+            if !out.iter().any(|f| f.path == path) {
+                out.push(Field {
+                    path: path.clone(),
+                    type_name: self.type_name(property),
+                    required: schema.required.contains(key),
+                    description: resolved.and_then(|p| p.description.clone()),
+                    enum_values: resolved.map_or(vec![], |p| p.enum_values.iter().map(enum_value).collect()),
+                });
+            }
+            self.collect_fields(property, &path, stack, out);
+        }
+        if let Some(items) = &schema.items {
+            self.collect_fields(items, &format!("{}[]", prefix), stack, out);
+        }
+
+        if name.is_some() {
+            stack.pop();
+        }
+    }
+
+    // names of the component schemas a schema refers to, through its properties,
+    // items and compositions too
+    fn schema_names<'a>(&'a self, schema: &'a RefOr<Schema>, out: &mut BTreeSet<&'a str>) {
+        let Ok((schema, name)) = self.resolve(schema) else {
+            return;
+        };
+        if let Some(name) = name {
+            if !out.insert(name) {
+                return;
+            }
+        }
+        let parts = schema.all_of.iter().chain(&schema.one_of).chain(&schema.any_of);
+        for part in parts.chain(schema.properties.values()).chain(schema.items.as_deref()) {
+            self.schema_names(part, out);
+        }
+    }
+
+    // the component schemas an operation's parameters, request body and responses use
+    pub fn operation_schemas(&self, item: &PathItem, op: &Operation) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+        for p in self.parameters(item, op) {
+            if let Some(schema) = &p.schema {
+                self.schema_names(schema, &mut names);
+            }
+        }
+        let bodies = op.request_body.iter().filter_map(|b| self.resolve(b).ok()).flat_map(|(b, _)| b.content.values());
+        let responses = op.responses.values().filter_map(|r| self.resolve(r).ok()).flat_map(|(r, _)| r.content.values());
+        for media_type in bodies.chain(responses) {
+            if let Some(schema) = &media_type.schema {
+                self.schema_names(schema, &mut names);
+            }
+        }
+        names.into_iter().map(|n| n.to_string()).collect()
+    }
+
+    // one document per operation: the first line is "METHOD /path: summary", then the
+    // operationId, tags, description, parameters, request body and responses with the
+    // field names of their schemas. Then one per components.schemas entry, see
+    // schema_document
+    pub fn documents(&self) -> Vec<Document> {
+        let mut docs = vec![];
+        let mut used_by: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (method, path, op) in self.operations() {
+            let item = &self.paths[path];
+            let id = format!("{} {}", method, path);
+            let summary = op.summary.clone().or_else(|| item.summary.clone()).unwrap_or_else(|| "No summary".to_string());
+            let mut text = format!("{}: {}\n", id, summary);
+            if let Some(operation_id) = &op.operation_id {
+                text.push_str(&format!("operationId: {}\n", operation_id));
+            }
+            if !op.tags.is_empty() {
+                text.push_str(&format!("tags: {}\n", op.tags.join(", ")));
+            }
+            if op.deprecated {
+                text.push_str("deprecated\n");
+            }
+            if let Some(description) = op.description.as_ref().or(item.description.as_ref()) {
+                text.push_str(&format!("{}\n", description.trim()));
+            }
+
+            let params = self.parameters(item, &op);
+            if !params.is_empty() {
+                text.push_str("parameters:\n");
+                for p in &params {
+                    let type_name = p.schema.as_ref().map_or("any".to_string(), |s| self.type_name(s));
+                    let field = Field {
+                        path: p.name.clone(),
+                        type_name: format!("{}, {}", p.location, type_name),
+                        required: p.required,
+                        description: p.description.clone(),
+                        enum_values: p.schema.as_ref()
+                            .and_then(|s| self.resolve(s).ok())
+                            .map_or(vec![], |(s, _)| s.enum_values.iter().map(enum_value).collect()),
+                    };
+                    text.push_str(&format_field(&field));
+                }
+            }
+
+            if let Some(body) = &op.request_body {
+                match self.resolve(body) {
+                    Ok((body, _)) => {
+                        let required = if body.required { ", required" } else { "" };
+                        // the other media types usually carry the same schema
+                        if let Some((media_type, content)) = body.content.iter().next() {
+                            let type_name = content.schema.as_ref().map_or("any".to_string(), |s| self.type_name(s));
+                            text.push_str(&format!("request body ({}{}): {}\n", media_type, required, type_name));
+                            if let Some(description) = &body.description {
+                                text.push_str(&format!("{}\n", description.trim()));
+                            }
+                            for field in content.schema.as_ref().map_or(vec![], |s| self.fields(s)) {
+                                text.push_str(&format_field(&field));
+                            }
+                        }
+                    }
+                    Err(e) => text.push_str(&format!("request body: {}\n", e)),
+                }
+            }
+
+            if !op.responses.is_empty() {
+                text.push_str("responses:\n");
+            }
+            for (status, response) in &op.responses {
+                let Ok((response, _)) = self.resolve(response) else {
+                    text.push_str(&format!("- {}\n", status));
+                    continue;
+                };
+                let schema = response.content.values().find_map(|c| c.schema.as_ref());
+                let mut line = format!("- {}: {}", status, response.description.as_deref().unwrap_or("").trim());
+                if let Some(schema) = schema {
+                    line.push_str(&format!(" -> {}", self.type_name(schema)));
+                }
+                text.push_str(&format!("{}\n", line.trim_end_matches([':', ' '])));
+                // the fields of what a successful call returns
+                if status.starts_with('2') {
+                    for field in schema.map_or(vec![], |s| self.fields(s)) {
+                        text.push_str(&format!("  {}", format_field(&field)));
+                    }
+                }
+            }
+
+            let schemas = self.operation_schemas(item, &op);
+            if !schemas.is_empty() {
+                text.push_str(&format!("schemas: {}\n", schemas.iter().cloned().collect::<Vec<_>>().join(", ")));
+            }
+            let caller = match &op.operation_id {
+                Some(operation_id) => format!("{} ({})", id, operation_id),
+                None => id.clone(),
+            };
+            for name in &schemas {
+                used_by.entry(name.clone()).or_default().push(caller.clone());
+            }
+
+            let mut doc = Document::new(&id, &text)
+                .with_meta("method", method.as_str())
+                .with_meta("path", path);
+            if let Some(operation_id) = &op.operation_id {
+                doc = doc.with_meta("operation_id", operation_id.as_str());
+            }
+            if !op.tags.is_empty() {
+                doc = doc.with_meta("tags", op.tags.join(", "));
+            }
+            if !schemas.is_empty() {
+                doc = doc.with_meta("schemas", schemas.into_iter().collect::<Vec<_>>().join(", "));
+            }
+            docs.push(doc);
+        }
+        for name in self.components.schemas.keys() {
+            let callers = used_by.get(name).map_or(&[][..], |c| c.as_slice());
+            docs.push(self.schema_document(name, callers));
+        }
+        docs
+    }
+
+    // "schema Task" with its type, description, enum values, fields and the operations
+    // using it, so a question can land on the model as well as the endpoint
+    fn schema_document(&self, name: &str, used_by: &[String]) -> Document {
+        let component = &self.components.schemas[name];
+        let id = format!("schema {}", name);
+        let mut text = format!("{}\ntype: {}\n", id, self.type_name(component));
+        if let Ok((schema, _)) = self.resolve(component) {
+            if let Some(description) = &schema.description {
+                text.push_str(&format!("{}\n", description.trim()));
+            }
+            if !schema.enum_values.is_empty() {
+                let values: Vec<String> = schema.enum_values.iter().map(enum_value).collect();
+                text.push_str(&format!("one of: {}\n", values.join(", ")));
+            }
+        }
+        // through a $ref so a recursive schema stops at itself
+        let reference = RefOr::Ref { reference: format!("#/components/schemas/{}", name.replace('~', "~0").replace('/', "~1")) };
+        let fields = self.fields(&reference);
+        if !fields.is_empty() {
+            text.push_str("fields:\n");
+            for field in fields {
+                text.push_str(&format_field(&field));
+            }
+        }
+        if !used_by.is_empty() {
+            text.push_str("used by:\n");
+            for caller in used_by {
+                text.push_str(&format!("- {}\n", caller));
+            }
+        }
+        let mut doc = Document::new(&id, &text).with_meta("schema", name);
+        if !used_by.is_empty() {
+            doc = doc.with_meta("operations", used_by.join(", "));
+        }
+        doc
+    }
+}
+
+// "- owner.id (string, required): who owns it, one of: a, b"
+fn format_field(field: &Field) -> String {
+    let mut line = format!("- {} ({}", field.path, field.type_name);
+    if field.required {
+        line.push_str(", required");
+    }
+    line.push(')');
+    if let Some(description) = &field.description {
+        line.push_str(&format!(": {}", description.trim().replace('\n', " ")));
+    }
+    if !field.enum_values.is_empty() {
+        line.push_str(&format!(" one of: {}", field.enum_values.join(", ")));
+    }
+    line.push('\n');
+    line
+}
+
+// rewrites a Swagger 2.0 document into the OpenAPI 3.0 shape Spec reads: definitions
+// become components.schemas, body and formData parameters request bodies, response
+// schemas response content, and basePath is prefixed to every path
+fn swagger_to_openapi(doc: Value) -> Value {
+    let media_types = |value: Option<&Value>| -> Vec<String> {
+        let types: Vec<String> = value.and_then(|v| v.as_array())
+            .map_or(vec![], |a| a.iter().filter_map(|t| t.as_str().map(|t| t.to_string())).collect());
+        if types.is_empty() { vec!["application/json".to_string()] } else { types }
+    };
+    let consumes = media_types(doc.get("consumes"));
+    let produces = media_types(doc.get("produces"));
+    let base_path = doc.get("basePath").and_then(|b| b.as_str()).unwrap_or("").trim_end_matches('/').to_string();
+
+    // shared parameters that are really request bodies
+    let shared_params = doc.get("parameters").and_then(|p| p.as_object()).cloned().unwrap_or_default();
+    let body_params: Vec<String> = shared_params.iter()
+        .filter(|(_, p)| p.get("in").and_then(|i| i.as_str()) == Some("body"))
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    let mut parameters = serde_json::Map::new();
+    let mut request_bodies = serde_json::Map::new();
+    for (name, param) in shared_params {
+        if body_params.contains(&name) {
+            request_bodies.insert(name, swagger_body(&param, &consumes));
+        } else {
+            parameters.insert(name, swagger_parameter(param));
+        }
+    }
+    let responses: serde_json::Map<String, Value> = doc.get("responses").and_then(|r| r.as_object()).cloned().unwrap_or_default()
+        .into_iter()
+        .map(|(name, response)| (name, swagger_response(response, &produces)))
+        .collect();
+
+    let mut paths = serde_json::Map::new();
+    for (path, item) in doc.get("paths").and_then(|p| p.as_object()).cloned().unwrap_or_default() {
+        let Value::Object(mut item) = item else {
+            continue;
+        };
+        let path_params = item.remove("parameters").and_then(|p| p.as_array().cloned()).unwrap_or_default();
+        let (path_body, path_params) = swagger_parameters(path_params, &consumes, &body_params);
+        if !path_params.is_empty() {
+            item.insert("parameters".to_string(), Value::Array(path_params));
+        }
+        for method in METHODS {
+            let Some(Value::Object(op)) = item.get_mut(method) else {
+                continue;
+            };
+            let consumes = op.get("consumes").map_or(consumes.clone(), |c| media_types(Some(c)));
+            let produces = op.get("produces").map_or(produces.clone(), |p| media_types(Some(p)));
+            let params = op.remove("parameters").and_then(|p| p.as_array().cloned()).unwrap_or_default();
+            let (body, params) = swagger_parameters(params, &consumes, &body_params);
+            op.insert("parameters".to_string(), Value::Array(params));
+            if let Some(body) = body.or_else(|| path_body.clone()) {
+                op.insert("requestBody".to_string(), body);
+            }
+            if let Some(Value::Object(op_responses)) = op.get_mut("responses") {
+                for response in op_responses.values_mut() {
+                    *response = swagger_response(response.take(), &produces);
+                }
+            }
+        }
+        paths.insert(format!("{}{}", base_path, path), Value::Object(item));
+    }
+
+    let mut res = serde_json::json!({
+        "openapi": "3.0.0",
+        "info": doc.get("info").cloned().unwrap_or(Value::Null),
+        "paths": paths,
+        "components": {
+            "schemas": doc.get("definitions").cloned().unwrap_or(Value::Object(Default::default())),
+            "parameters": parameters,
+            "requestBodies": request_bodies,
+            "responses": responses,
+        },
+    });
+    if res["info"].is_null() {
+        res.as_object_mut().unwrap().remove("info");
+    }
+    rewrite_swagger_refs(&mut res, &body_params);
+    res
+}
+
+// (request body, remaining parameters) of an operation's or path's parameter list.
+// formData parameters are gathered into one form body
+fn swagger_parameters(params: Vec<Value>, consumes: &[String], body_params: &[String]) -> (Option<Value>, Vec<Value>) {
+    let mut body = None;
+    let mut form = serde_json::Map::new();
+    let mut form_required = vec![];
+    let mut rest = vec![];
+    for param in params {
+        if let Some(reference) = param.get("$ref").and_then(|r| r.as_str()) {
+            match reference.strip_prefix("#/parameters/") {
+                Some(name) if body_params.iter().any(|b| b == name) => {
+                    body = Some(serde_json::json!({ "$ref": format!("#/components/requestBodies/{}", name) }));
+                }
+                _ => rest.push(param),
+            }
+            continue;
+        }
+        match param.get("in").and_then(|i| i.as_str()) {
+            Some("body") => body = Some(swagger_body(&param, consumes)),
+            Some("formData") => {
+                let name = param.get("name").and_then(|n| n.as_str()).unwrap_or("").to_string();
+                if param.get("required").and_then(|r| r.as_bool()) == Some(true) {
+                    form_required.push(Value::String(name.clone()));
+                }
+                let description = param.get("description").cloned();
+                let mut schema = swagger_parameter(param)["schema"].take();
+                if let (Value::Object(schema), Some(description)) = (&mut schema, description) {
+                    schema.insert("description".to_string(), description);
+                }
+                form.insert(name, schema);
+            }
+            _ => rest.push(swagger_parameter(param)),
+        }
+    }
+    if body.is_none() && !form.is_empty() {
+        let media_type = consumes.iter()
+            .find(|c| c.starts_with("multipart/") || c.starts_with("application/x-www-form-urlencoded"))
+            .cloned()
+            .unwrap_or_else(|| "application/x-www-form-urlencoded".to_string());
+        body = Some(serde_json::json!({
+            "content": { media_type: { "schema": { "type": "object", "properties": form, "required": form_required } } }
+        }));
+    }
+    (body, rest)
+}
+
+// query, path and header parameters keep their fields, type and friends move into a schema
+fn swagger_parameter(mut param: Value) -> Value {
+    let Value::Object(fields) = &mut param else {
+        return param;
+    };
+    if fields.contains_key("$ref") || fields.contains_key("schema") {
+        return param;
+    }
+    let mut schema = serde_json::Map::new();
+    for key in ["type", "format", "items", "enum", "default", "minimum", "maximum", "pattern"] {
+        if let Some(value) = fields.remove(key) {
+            schema.insert(key.to_string(), value);
+        }
+    }
+    if schema.get("type").and_then(|t| t.as_str()) == Some("file") {
+        schema.insert("type".to_string(), Value::from("string"));
+        schema.insert("format".to_string(), Value::from("binary"));
+    }
+    fields.insert("schema".to_string(), Value::Object(schema));
+    param
+}
+
+fn swagger_body(param: &Value, consumes: &[String]) -> Value {
+    let schema = param.get("schema").cloned().unwrap_or(Value::Null);
+    let content: serde_json::Map<String, Value> = consumes.iter()
+        .map(|c| (c.clone(), serde_json::json!({ "schema": schema })))
+        .collect();
+    let mut body = serde_json::json!({
+        "required": param.get("required").cloned().unwrap_or(Value::Bool(false)),
+        "content": content,
+    });
+    if let Some(description) = param.get("description") {
+        body["description"] = description.clone();
+    }
+    body
+}
+
+fn swagger_response(mut response: Value, produces: &[String]) -> Value {
+    let Value::Object(fields) = &mut response else {
+        return response;
+    };
+    if let Some(schema) = fields.remove("schema") {
+        let content: serde_json::Map<String, Value> = produces.iter()
+            .map(|p| (p.clone(), serde_json::json!({ "schema": schema })))
+            .collect();
+        fields.insert("content".to_string(), Value::Object(content));
+    }
+    response
+}
+
+// points Swagger's #/definitions, #/parameters and #/responses refs at components
+fn rewrite_swagger_refs(value: &mut Value, body_params: &[String]) {
+    match value {
+        Value::Object(fields) => {
+            if let Some(Value::String(reference)) = fields.get_mut("$ref") {
+                let rewritten = if let Some(name) = reference.strip_prefix("#/definitions/") {
+                    format!("#/components/schemas/{}", name)
+                } else if let Some(name) = reference.strip_prefix("#/parameters/") {
+                    let kind = if body_params.iter().any(|b| b == name) { "requestBodies" } else { "parameters" };
+                    format!("#/components/{}/{}", kind, name)
+                } else if let Some(name) = reference.strip_prefix("#/responses/") {
+                    format!("#/components/responses/{}", name)
+                } else {
+                    reference.clone()
+                };
+                *reference = rewritten;
+            }
+            for child in fields.values_mut() {
+                rewrite_swagger_refs(child, body_params);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                rewrite_swagger_refs(item, body_params);
+            }
+        }
+        _ => {}
+    }
+}
+
+// "3.0.3", or a number for versions written unquoted in YAML, e.g. `swagger: 2.0`
+fn version_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(version) => Some(version.clone()),
+        Value::Number(version) => Some(version.to_string()),
+        _ => None,
+    }
+}
+
+// YAML mappings can have non-string keys, unquoted status codes like `200:` are numbers
+fn yaml_to_json(value: serde_yaml::Value) -> Value {
+    use serde_yaml::Value as Yaml;
+    match value {
+        Yaml::Null => Value::Null,
+        Yaml::Bool(b) => Value::Bool(b),
+        Yaml::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+            (Some(i), _, _) => Value::from(i),
+            (_, Some(u), _) => Value::from(u),
+            (_, _, Some(f)) => serde_json::Number::from_f64(f).map_or(Value::Null, Value::Number),
+            _ => Value::Null,
+        },
+        Yaml::String(s) => Value::String(s),
+        Yaml::Sequence(items) => Value::Array(items.into_iter().map(yaml_to_json).collect()),
+        Yaml::Mapping(map) => Value::Object(map.into_iter().map(|(k, v)| {
+            let key = match k {
+                Yaml::String(s) => s,
+                Yaml::Number(n) => n.to_string(),
+                Yaml::Bool(b) => b.to_string(),
+                other => serde_yaml::to_string(&other).unwrap_or_default().trim().to_string(),
+            };
+            (key, yaml_to_json(v))
+        }).collect()),
+        Yaml::Tagged(tagged) => yaml_to_json(tagged.value),
+    }
+}
+
+fn enum_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r##"{
+        "openapi": "3.0.3",
+        "info": {"title": "Tasks", "version": "1"},
+        "paths": {
+            "/tasks/{taskId}": {
+                "parameters": [{"$ref": "#/components/parameters/TaskId"}],
+                "get": {
+                    "operationId": "getTask",
+                    "summary": "Get a task",
+                    "tags": ["tasks"],
+                    "parameters": [{"name": "expand", "in": "query", "schema": {"type": "string", "enum": ["owner", "subtasks"]}}],
+                    "responses": {
+                        "200": {"description": "The task", "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Task"}}}},
+                        "404": {"$ref": "#/components/responses/NotFound"}
+                    }
+                },
+                "put": {
+                    "summary": "Replace a task",
+                    "requestBody": {"required": true, "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Task"}}}},
+                    "responses": {"204": {"description": "Replaced"}}
+                }
+            },
+            "/loop": {"get": {"responses": {"200": {"description": "x", "content": {"application/json": {"schema": {"$ref": "#/components/schemas/A"}}}}}}}
+        },
+        "components": {
+            "parameters": {"TaskId": {"name": "taskId", "in": "path", "required": true, "schema": {"type": "string", "format": "uuid"}}},
+            "responses": {"NotFound": {"description": "No such task"}},
+            "schemas": {
+                "Task": {
+                    "type": "object",
+                    "required": ["title"],
+                    "properties": {
+                        "title": {"type": "string", "description": "Short title"},
+                        "status": {"type": "string", "enum": ["open", "done"]},
+                        "owner": {"$ref": "#/components/schemas/User"},
+                        "subtasks": {"type": "array", "items": {"$ref": "#/components/schemas/Task"}}
+                    }
+                },
+                "User": {"allOf": [{"$ref": "#/components/schemas/Named"}, {"properties": {"id": {"type": "integer"}}}]},
+                "Named": {"properties": {"name": {"type": "string"}}},
+                "A": {"$ref": "#/components/schemas/B"},
+                "B": {"$ref": "#/components/schemas/A"}
+            }
+        }
+    }"##;
+
+    #[test]
+    fn test_operation_documents() {
+        let spec = Spec::from_json(SPEC).unwrap();
+        let docs = spec.documents();
+        let ids: Vec<&str> = docs.iter().map(|d| d.id.as_str()).collect();
+        assert_eq!(ids[..3], ["GET /loop", "GET /tasks/{taskId}", "PUT /tasks/{taskId}"]);
+
+        let get = &docs[1].text;
+        assert!(get.starts_with("GET /tasks/{taskId}: Get a task\noperationId: getTask\ntags: tasks\n"));
+        assert!(get.contains("- expand (query, string) one of: owner, subtasks\n"));
+        assert!(get.contains("- taskId (path, string(uuid), required)\n"));
+        assert!(get.contains("- 200: The task -> Task\n"));
+        assert!(get.contains("  - owner.name (string)\n"));
+        assert!(get.contains("  - owner.id (integer)\n"));
+        assert!(get.contains("  - subtasks (Task[])\n"));
+        assert!(get.contains("- 404: No such task\n"));
+        assert_eq!(docs[1].metadata["operation_id"], "getTask".into());
+
+        let put = &docs[2].text;
+        assert!(put.contains("request body (application/json, required): Task\n- owner (User)\n"));
+        assert!(put.contains("- title (string, required): Short title\n"));
+        assert!(put.contains("- status (string) one of: open, done\n"));
+        // path parameters apply to every operation
+        assert!(put.contains("- taskId (path"));
+
+        // the Task -> subtasks -> Task cycle is expanded once
+        assert!(!put.contains("subtasks[].subtasks"));
+        // A -> B -> A can't be resolved but doesn't stop the rest
+        assert!(docs[0].text.contains("- 200: x -> A\n"));
+    }
+
+    #[test]
+    fn test_schema_documents() {
+        let spec = Spec::from_json(SPEC).unwrap();
+        let docs = spec.documents();
+        let ids: Vec<&str> = docs.iter().map(|d| d.id.as_str()).collect();
+        assert_eq!(ids[3..], ["schema A", "schema B", "schema Named", "schema Task", "schema User"]);
+
+        // operations list the models they use, nested ones included
+        assert!(docs[1].text.ends_with("schemas: Named, Task, User\n"));
+        assert_eq!(docs[2].metadata["schemas"], "Named, Task, User".into());
+
+        let task = &docs[6].text;
+        assert!(task.starts_with("schema Task\ntype: object\nfields:\n"));
+        assert!(task.contains("- title (string, required): Short title\n"));
+        assert!(task.contains("- status (string) one of: open, done\n"));
+        assert!(task.contains("- owner.name (string)\n"));
+        assert!(!task.contains("subtasks[].title"));
+        assert!(task.ends_with("used by:\n- GET /tasks/{taskId} (getTask)\n- PUT /tasks/{taskId}\n"));
+        assert_eq!(docs[6].metadata["schema"], "Task".into());
+        assert_eq!(docs[6].metadata["operations"], "GET /tasks/{taskId} (getTask), PUT /tasks/{taskId}".into());
+
+        assert!(docs[5].text.contains("used by:\n- GET /tasks/{taskId} (getTask)\n"));
+        // the A <-> B cycle has no fields and isn't linked to anything
+        assert!(!docs[3].text.contains("used by"));
+        assert!(!docs[3].metadata.contains_key("operations"));
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let spec = Spec::from_json(SPEC).unwrap();
+        let a = RefOr::<Schema>::Ref { reference: "#/components/schemas/A".to_string() };
+        assert!(spec.resolve(&a).unwrap_err().starts_with("cyclic $ref"));
+        let missing = RefOr::<Schema>::Ref { reference: "#/components/schemas/Missing".to_string() };
+        assert!(spec.resolve(&missing).unwrap_err().starts_with("unresolved $ref"));
+        let external = RefOr::<Schema>::Ref { reference: "other.yaml#/Task".to_string() };
+        assert!(spec.resolve(&external).unwrap_err().starts_with("unsupported $ref"));
+        assert!(Spec::from_json(r#"{"swagger": "1.2", "paths": {}}"#).unwrap_err().contains("unsupported Swagger version"));
+    }
+
+    #[test]
+    fn test_parse_yaml() {
+        let yaml = r##"
+openapi: 3.0.0
+info: {title: Tasks, version: "1"}
+paths:
+  /tasks:
+    post:
+      operationId: createTask
+      requestBody:
+        content:
+          application/json:
+            schema: {$ref: "#/components/schemas/Task"}
+      responses:
+        201:
+          description: Created
+components:
+  schemas:
+    Task:
+      properties:
+        title: {type: string}
+"##;
+        let docs = Spec::parse(yaml).unwrap().documents();
+        assert_eq!(docs.len(), 2);
+        assert!(docs[0].text.contains("operationId: createTask\n"));
+        assert!(docs[0].text.contains("- title (string)\n"));
+        assert!(docs[0].text.contains("- 201: Created\n"));
+
+        assert_eq!(Spec::parse(SPEC).unwrap().paths.len(), 2);
+        assert!(Spec::parse("title: notes\n").unwrap_err().contains("no openapi or swagger version"));
+        assert!(Spec::parse("openapi: [3\n").unwrap_err().starts_with("invalid YAML"));
+        assert!(Spec::parse("{\"openapi\": ").unwrap_err().starts_with("invalid JSON"));
+    }
+
+    #[test]
+    fn test_swagger() {
+        let yaml = r##"
+swagger: "2.0"
+info: {title: Tasks, version: "1"}
+basePath: /v1/
+consumes: [application/json]
+parameters:
+  TaskBody:
+    name: task
+    in: body
+    required: true
+    schema: {$ref: "#/definitions/Task"}
+  Limit: {name: limit, in: query, type: integer, format: int32}
+paths:
+  /tasks:
+    get:
+      summary: List tasks
+      parameters:
+        - $ref: "#/parameters/Limit"
+        - {name: status, in: query, type: string, enum: [open, done]}
+      responses:
+        200:
+          description: The tasks
+          schema: {type: array, items: {$ref: "#/definitions/Task"}}
+    post:
+      parameters:
+        - $ref: "#/parameters/TaskBody"
+      responses:
+        201: {description: Created, schema: {$ref: "#/definitions/Task"}}
+  /tasks/{id}/attachments:
+    post:
+      consumes: [multipart/form-data]
+      parameters:
+        - {name: id, in: path, required: true, type: string}
+        - {name: file, in: formData, required: true, type: file, description: The upload}
+      responses:
+        204: {description: Attached}
+definitions:
+  Task:
+    required: [title]
+    properties:
+      title: {type: string}
+"##;
+        let spec = Spec::parse(yaml).unwrap();
+        let docs = spec.documents();
+        assert_eq!(docs.len(), 4);
+        let doc = |id: &str| docs.iter().find(|d| d.text.starts_with(id)).unwrap();
+
+        let list = doc("GET /v1/tasks: List tasks\n");
+        assert!(list.text.contains("- limit (query, integer(int32))"), "{}", list.text);
+        assert!(list.text.contains("one of: open, done"));
+        assert!(list.text.contains("- 200: The tasks -> Task[]\n"));
+        assert!(list.text.contains("  - [].title (string, required)\n"));
+
+        let create = doc("POST /v1/tasks");
+        assert!(create.text.contains("request body (application/json, required): Task\n"), "{}", create.text);
+        assert!(create.text.contains("- 201: Created -> Task\n"));
+
+        let attach = doc("POST /v1/tasks/{id}/attachments");
+        assert!(attach.text.contains("- id (path, string, required)"), "{}", attach.text);
+        assert!(attach.text.contains("request body (multipart/form-data)"));
+        assert!(attach.text.contains("- file (string(binary), required): The upload\n"));
+        // definitions are indexed as schemas
+        assert!(doc("schema Task").text.contains("- title (string, required)\n"));
+
+        // unquoted versions are YAML numbers
+        let unquoted = yaml.replace("swagger: \"2.0\"", "swagger: 2.0");
+        assert_eq!(Spec::parse(&unquoted).unwrap().documents().len(), 4);
+        let spec = Spec::parse("openapi: 3.0\npaths: {}\n").unwrap();
+        assert_eq!(spec.openapi, "3.0");
+        assert!(Spec::parse("swagger: 1.2\n").unwrap_err().contains("unsupported Swagger version 1.2"));
+    }
+}
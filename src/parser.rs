@@ -1,45 +1,305 @@
-use serde::Deserialize;
-use std::{collections::HashMap, fs};
+use std::collections::BTreeMap;
 use ureq;
-#[derive(Debug, Deserialize)]
-struct OpenAPI {
-    paths: HashMap<String, HashMap<String, Operation>>,
+use crate::document::Document;
+use crate::grep;
+use crate::openapi;
+use crate::utils;
+use std::fs;
+use std::path::{Path, PathBuf};
+// one document per operation, tagged with its method, path and operationId, see
+// openapi::Spec::documents. `location` is an http(s) url, a file:// url or a path to a
+// JSON or YAML spec
+pub fn parse_openapi(location: &str) -> Result<Vec<Document>, Box<dyn std::error::Error>> {
+    parse_openapi_content(location, &fetch(location)?)
 }
 
-#[derive(Debug, Deserialize)]
-struct Operation {
-    summary: Option<String>,
+// parse_openapi for a spec already fetched from `location`
+pub fn parse_openapi_content(location: &str, content: &str) -> Result<Vec<Document>, Box<dyn std::error::Error>> {
+    let spec = openapi::Spec::parse(content).map_err(|e| format!("{}: {}", location, e))?;
+    Ok(spec.documents())
 }
 
-pub fn parse_openapi(url: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let content: String = ureq::get(url)
-        .header("Example-Header", "header value")
-        .call()?
-        .body_mut()
-        .read_to_string()?;
-    // let file_content = fs::read_to_string(file_path)?;
-    let openapi: OpenAPI = serde_json::from_str(&content)?;
+// one document per class/struct definition under root, keyed by file:start-end
+pub fn parse_codebase(root_path: &str) -> Result<Vec<Document>, Box<dyn std::error::Error>> {
+    let root = Path::new(root_path);
+    if !root.is_dir() {
+        return Err(format!("{} is not a directory", root_path).into());
+    }
+    let mut docs: Vec<Document> = grep::find_class_definitions(root, None)?
+        .iter()
+        .map(|class| class.to_document())
+        .collect();
+    // directory order differs between platforms, keep the index stable
+    docs.sort_by(|a, b| a.id.cmp(&b.id));
+    docs.dedup_by(|a, b| a.id == b.id);
+    Ok(docs)
+}
+
+// prose files indexed by parse_docs when given a directory or glob
+pub const DOC_EXTENSIONS: [&str; 5] = ["md", "markdown", "txt", "rst", "adoc"];
+
+// the body of an http(s) url, otherwise the file at a path or file:// url
+pub fn fetch(location: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        let mut response = ureq::get(location).call().map_err(|e| format!("{}: {}", location, e))?;
+        return Ok(response.body_mut().read_to_string().map_err(|e| format!("{}: {}", location, e))?);
+    }
+    let path = location.strip_prefix("file://").unwrap_or(location);
+    fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e).into())
+}
+
+// a top level "openapi" or "swagger" key, in JSON or YAML
+pub fn looks_like_openapi(content: &str) -> bool {
+    let json = ["\"openapi\"", "\"swagger\""].iter().any(|key| {
+        content.match_indices(key).any(|(i, _)| content[i + key.len()..].trim_start().starts_with(':'))
+    });
+    json || content.lines().any(|l| l.starts_with("openapi:") || l.starts_with("swagger:"))
+}
+
+// one document per file, keyed by path and spanning all its lines. `source` is a url, a
+// file, a directory (its DOC_EXTENSIONS files) or a glob
+pub fn parse_docs(source: &str) -> Result<Vec<Document>, Box<dyn std::error::Error>> {
+    let path = Path::new(source.strip_prefix("file://").unwrap_or(source));
+    let files: Vec<PathBuf> = if !utils::is_url(source) && utils::is_glob(source) {
+        utils::glob(source)?
+    } else if path.is_dir() {
+        utils::walk_files(path)?.into_iter()
+            .filter(|f| f.extension().and_then(|e| e.to_str()).is_some_and(|e| DOC_EXTENSIONS.contains(&e)))
+            .collect()
+    } else {
+        let text = fetch(source)?;
+        return Ok(vec![line_document(source, &text)]);
+    };
+
+    let mut docs = vec![];
+    for file in files {
+        // binaries and other encodings aren't docs
+        if let Ok(text) = fs::read_to_string(&file) {
+            docs.push(line_document(&file.to_string_lossy(), &text));
+        }
+    }
+    Ok(docs)
+}
 
-    let mut output = String::new();
+pub fn line_document(file: &str, text: &str) -> Document {
+    Document::new(file, text)
+        .with_meta("file", file)
+        .with_meta("start_line", 1usize)
+        .with_meta("end_line", text.lines().count().max(1))
+}
+
+// tags a snippet's front matter can carry, any of them marks a file as a snippet
+pub const SNIPPET_TAGS: [&str; 3] = ["language", "topic", "difficulty"];
+
+// a curated example: what it shows, the code, and tags to filter on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snippet {
+    pub file: String,
+    pub title: String,
+    pub explanation: String,
+    pub code: String,
+    // front matter keys, lowercased values. language is filled from the code fence or
+    // file extension when the front matter has none
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Snippet {
+    // markdown files keep their prose as the explanation and their fenced blocks as the
+    // code, code files their leading comments and the rest
+    pub fn parse(file: &str, text: &str) -> Self {
+        let (mut tags, body) = front_matter(text);
+        let is_markdown = Path::new(file).extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| DOC_EXTENSIONS.contains(&e));
+
+        let mut title = tags.remove("title");
+        let mut explanation = vec![];
+        let mut code = vec![];
+        if is_markdown {
+            let mut fence: Option<String> = None;
+            for line in body.lines() {
+                match (&fence, line.trim_start().strip_prefix("```")) {
+                    (None, Some(info)) => fence = Some(info.trim().to_lowercase()),
+                    (Some(info), Some(_)) => {
+                        if !info.is_empty() {
+                            tags.entry("language".to_string()).or_insert_with(|| info.clone());
+                        }
+                        fence = None;
+                        code.push("");
+                    }
+                    (Some(_), None) => code.push(line),
+                    (None, None) => match line.strip_prefix("# ") {
+                        Some(heading) if title.is_none() => title = Some(heading.trim().to_string()),
+                        _ => explanation.push(line),
+                    },
+                }
+            }
+        } else {
+            let mut lines = body.lines().peekable();
+            while let Some(comment) = lines.peek().and_then(|l| strip_comment(l)) {
+                explanation.push(comment);
+                lines.next();
+            }
+            code.extend(lines);
+            let language = grep::get_language_from_extension(Path::new(file));
+            if language != "unknown" {
+                tags.entry("language".to_string()).or_insert(language);
+            }
+        }
+
+        let title = title.unwrap_or_else(|| {
+            Path::new(file).file_stem().map_or(file.to_string(), |s| s.to_string_lossy().replace(['_', '-'], " "))
+        });
+        Self {
+            file: file.to_string(),
+            title,
+            explanation: explanation.join("\n").trim().to_string(),
+            code: code.join("\n").trim().to_string(),
+            tags,
+        }
+    }
+
+    // title, explanation and code as one markdown text, tags as metadata
+    pub fn to_document(&self) -> Document {
+        let language = self.tags.get("language").map_or("", |l| l.as_str());
+        let mut text = format!("# {}\n\n", self.title);
+        if !self.explanation.is_empty() {
+            text.push_str(&format!("{}\n\n", self.explanation));
+        }
+        if !self.code.is_empty() {
+            text.push_str(&format!("```{}\n{}\n```\n", language, self.code));
+        }
+        let mut doc = Document::new(&self.file, &text)
+            .with_meta("file", self.file.as_str())
+            .with_meta("title", self.title.as_str());
+        for (key, value) in &self.tags {
+            doc = doc.with_meta(key, value.as_str());
+        }
+        doc
+    }
+}
 
-    for (path, methods) in openapi.paths {
-        for (method, op) in methods {
-            let summary = op.summary.unwrap_or_else(|| "No summary".to_string());
-            output.push_str(&format!(
-                "{} {}: {}\n",
-                method.to_uppercase(), path, summary
-            ));
+// "key: value" lines between leading --- lines, which may sit in comments for code
+// files. returns the tags and the text after the block
+fn front_matter(text: &str) -> (BTreeMap<String, String>, &str) {
+    let mut tags = BTreeMap::new();
+    let mut lines = text.split_inclusive('\n');
+    let uncomment = |l: &str| match l.trim() {
+        "---" => "---".to_string(),
+        l => strip_comment(l).unwrap_or(l).to_string(),
+    };
+    if lines.next().map(uncomment).as_deref() != Some("---") {
+        return (tags, text);
+    }
+    let mut consumed = text.split_inclusive('\n').next().unwrap().len();
+    for line in lines {
+        consumed += line.len();
+        let line = uncomment(line);
+        if line == "---" {
+            return (tags, &text[consumed..]);
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            // "[threads, sync]" lists are kept as "threads, sync"
+            let value = value.trim().trim_matches(|c| matches!(c, '"' | '\'' | '[' | ']'));
+            let value = if key.trim() == "title" { value.to_string() } else { value.to_lowercase() };
+            tags.insert(key.trim().to_lowercase(), value);
         }
     }
+    // never closed, not front matter
+    (BTreeMap::new(), text)
+}
 
-    Ok(output)
+// the text of a //, # or -- comment line
+fn strip_comment(line: &str) -> Option<&str> {
+    let line = line.trim();
+    ["///", "//", "#", "--"].iter()
+        .find_map(|prefix| line.strip_prefix(prefix))
+        .map(|rest| rest.strip_prefix(' ').unwrap_or(rest).trim_end())
 }
 
-pub fn parse_codebase(root_path: &str) -> Result<String, Box<dyn std::error::Error>> {
-    // 1. determine languages
-    // 2. rg codebase for "type" or "class" depending on langs
-    // 3. for each match, jump to file and parse that class, load into memory
-    // Extra: create class dependency tree
+// true when the file opens with front matter carrying one of SNIPPET_TAGS
+pub fn has_snippet_tags(text: &str) -> bool {
+    let (tags, _) = front_matter(text);
+    SNIPPET_TAGS.iter().any(|t| tags.contains_key(*t))
+}
 
-    Ok("Test".to_string())
-}
\ No newline at end of file
+// one document per markdown or code file under dir, see Snippet
+pub fn parse_snippets(dir: &str) -> Result<Vec<Document>, Box<dyn std::error::Error>> {
+    let root = Path::new(dir.strip_prefix("file://").unwrap_or(dir));
+    if !root.is_dir() {
+        return Err(format!("{} is not a directory", dir).into());
+    }
+    let mut docs = vec![];
+    for file in utils::walk_files(root)? {
+        let Some(ext) = file.extension().and_then(|e| e.to_str()) else {
+            continue;
+        };
+        if !DOC_EXTENSIONS.contains(&ext) && !grep::CODE_EXTENSIONS.contains(&ext) {
+            continue;
+        }
+        if let Ok(text) = fs::read_to_string(&file) {
+            docs.push(Snippet::parse(&file.to_string_lossy(), &text).to_document());
+        }
+    }
+    Ok(docs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_looks_like_openapi() {
+        assert!(looks_like_openapi(r#"{"openapi":"3.0.0","paths":{}}"#));
+        assert!(looks_like_openapi("{\n  \"swagger\" : \"2.0\"\n}"));
+        assert!(looks_like_openapi("# api\nopenapi: 3.1.0\npaths: {}\n"));
+        assert!(!looks_like_openapi("The openapi: key starts a spec"));
+        assert!(!looks_like_openapi(r#"{"title": "openapi"}"#));
+    }
+
+    #[test]
+    fn test_parse_openapi_from_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let yaml = dir.path().join("openapi.yaml");
+        fs::write(&yaml, "openapi: 3.1.0\npaths:\n  /tasks:\n    get:\n      summary: List tasks\n").unwrap();
+        let path = yaml.to_str().unwrap();
+        let docs = parse_openapi(path).unwrap();
+        assert_eq!(docs[0].text, "GET /tasks: List tasks\n");
+        assert_eq!(parse_openapi(&format!("file://{}", path)).unwrap()[0].id, "GET /tasks");
+
+        let err = parse_openapi(&format!("{}.missing", path)).unwrap_err().to_string();
+        assert!(err.contains("openapi.yaml.missing"));
+        let json = dir.path().join("openapi.json");
+        fs::write(&json, "{\"info\": {}}").unwrap();
+        let err = parse_openapi(json.to_str().unwrap()).unwrap_err().to_string();
+        assert!(err.contains("openapi.json: not an OpenAPI document"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_snippets() {
+        let md = "---\ntitle: Start a thread\nlanguage: Rust\ntopic: [threads, concurrency]\ndifficulty: beginner\n---\n\
+                  # ignored heading\nSpawn a closure on a new OS thread and join it.\n\n```rust\nlet h = std::thread::spawn(|| 1);\nh.join().unwrap();\n```\n";
+        let snippet = Snippet::parse("snippets/thread.md", md);
+        assert_eq!(snippet.title, "Start a thread");
+        assert_eq!(snippet.explanation, "# ignored heading\nSpawn a closure on a new OS thread and join it.");
+        assert_eq!(snippet.code, "let h = std::thread::spawn(|| 1);\nh.join().unwrap();");
+        assert_eq!(snippet.tags["language"], "rust");
+        assert_eq!(snippet.tags["topic"], "threads, concurrency");
+        assert!(has_snippet_tags(md));
+
+        let doc = snippet.to_document();
+        assert!(doc.text.starts_with("# Start a thread\n\n# ignored heading\nSpawn"));
+        assert!(doc.text.ends_with("```rust\nlet h = std::thread::spawn(|| 1);\nh.join().unwrap();\n```\n"));
+        assert_eq!(doc.metadata["difficulty"], "beginner".into());
+
+        let py = "# ---\n# topic: files\n# ---\n# Read a file line by line.\nwith open(p) as f:\n    for line in f: print(line)\n";
+        let snippet = Snippet::parse("snippets/read_lines.py", py);
+        assert_eq!((snippet.title.as_str(), snippet.explanation.as_str()), ("read lines", "Read a file line by line."));
+        assert_eq!(snippet.tags["language"], "python");
+        assert!(snippet.code.starts_with("with open(p)"));
+
+        assert!(!has_snippet_tags("# Notes\n---\nlanguage: rust\n---\n"));
+        let untagged = Snippet::parse("notes.md", "# Notes\nplain text\n");
+        assert_eq!((untagged.title.as_str(), untagged.tags.len()), ("Notes", 0));
+    }
+}
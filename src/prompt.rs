@@ -0,0 +1,210 @@
+use crate::app::Chunk;
+use crate::document::MetaValue;
+use std::env;
+use std::fs;
+use std::path::Path;
+use ort::Error as OrtError;
+
+// <name>.txt files here override the built-in templates, change with MINI_RAG_PROMPT_DIR
+pub const DEFAULT_PROMPT_DIR: &str = "src/prompts";
+
+const BUILTIN: [(&str, &str); 4] = [
+    ("default", include_str!("prompts/default.txt")),
+    ("openapi", include_str!("prompts/openapi.txt")),
+    ("codebase", include_str!("prompts/codebase.txt")),
+    ("educational", include_str!("prompts/educational.txt")),
+];
+
+// a prompt with {question}, {chunks}, {sources} and {history} placeholders
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptTemplate {
+    pub name: String,
+    pub text: String,
+}
+
+impl PromptTemplate {
+    pub fn new(name: &str, text: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    // <prompt dir>/<name>.txt, or else the built-in template of that name
+    pub fn load(name: &str) -> Result<Self, OrtError> {
+        let dir = env::var("MINI_RAG_PROMPT_DIR").unwrap_or_else(|_| DEFAULT_PROMPT_DIR.to_string());
+        Self::load_from(Path::new(&dir), name)
+    }
+
+    pub fn load_from(dir: &Path, name: &str) -> Result<Self, OrtError> {
+        // names become file names, so nothing that could leave the prompt dir
+        let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid {
+            return Err(OrtError::new(format!(
+                "invalid prompt template name {:?}: use letters, digits, '_' and '-'",
+                name
+            )));
+        }
+        let path = dir.join(format!("{}.txt", name));
+        if let Ok(text) = fs::read_to_string(&path) {
+            return Ok(Self::new(name, &text));
+        }
+        BUILTIN.iter()
+            .find(|(builtin, _)| *builtin == name)
+            .map(|(_, text)| Self::new(name, text))
+            .ok_or_else(|| OrtError::new(format!("no prompt template {} in {}", name, dir.display())))
+    }
+
+    // chunks are numbered from 1 in the given order, the numbers answers cite
+    pub fn render(&self, question: &str, chunks: &[&Chunk], history: &[(String, String)]) -> String {
+        let mut context = String::new();
+        let mut sources = String::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            context.push_str(&format!("[{}] {}\n\n", i + 1, chunk.text.trim()));
+            sources.push_str(&format!("[{}] {}\n", i + 1, source_label(chunk)));
+        }
+        let mut turns = String::new();
+        if !history.is_empty() {
+            turns.push_str("Conversation so far:\n");
+            for (q, a) in history {
+                turns.push_str(&format!("User: {}\nAssistant: {}\n", q, a));
+            }
+            turns.push('\n');
+        }
+
+        fill(&self.text, &[
+            ("history", &turns),
+            ("sources", sources.trim_end()),
+            ("chunks", &context),
+            ("question", question),
+        ])
+    }
+}
+
+// replaces every {name} of the template in one pass, so a chunk or an earlier answer
+// that happens to contain "{question}" is left as it is
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut res = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        res.push_str(&rest[..start]);
+        let tail = &rest[start + 1..];
+        match values.iter().find(|(name, _)| tail.starts_with(name) && tail[name.len()..].starts_with('}')) {
+            Some((name, value)) => {
+                res.push_str(value);
+                rest = &tail[name.len() + 1..];
+            }
+            None => {
+                res.push('{');
+                rest = tail;
+            }
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+// file:start-end for code, the document id otherwise
+pub fn source_label(chunk: &Chunk) -> String {
+    let meta = &chunk.metadata;
+    match (meta.get("file"), meta.get("start_line"), meta.get("end_line")) {
+        (Some(file), Some(MetaValue::Int(start)), Some(MetaValue::Int(end))) => format!("{}:{}-{}", file, start, end),
+        _ => chunk.doc_id.clone(),
+    }
+}
+
+// [n] markers in the answer that refer to one of n_chunks, in order of first use
+pub fn citations(answer: &str, n_chunks: usize) -> Vec<usize> {
+    let mut res = vec![];
+    for part in answer.split('[').skip(1) {
+        let Some((n, _)) = part.split_once(']') else {
+            continue;
+        };
+        // also accept "[1, 3]"
+        for n in n.split(',').filter_map(|n| n.trim().parse::<usize>().ok()) {
+            if (1..=n_chunks).contains(&n) && !res.contains(&n) {
+                res.push(n);
+            }
+        }
+    }
+    res
+}
+
+// the answer followed by the sources it cites, or every source if it cites none
+pub fn with_sources(answer: &str, chunks: &[&Chunk]) -> String {
+    let mut cited = citations(answer, chunks.len());
+    if cited.is_empty() {
+        cited = (1..=chunks.len()).collect();
+    }
+    let mut res = format!("{}\n\nSources:\n", answer.trim());
+    for n in cited {
+        res.push_str(&format!("[{}] {}\n", n, source_label(chunks[n - 1])));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Metadata;
+    use tempfile::TempDir;
+
+    fn chunk(doc_id: &str, text: &str, meta: &[(&str, MetaValue)]) -> Chunk {
+        let metadata: Metadata = meta.iter().map(|(k, v)| (k.to_string(), v.clone())).collect();
+        Chunk { doc_id: doc_id.to_string(), text: text.to_string(), offset: 0, metadata }
+    }
+
+    #[test]
+    fn test_render_and_cite() {
+        let post = chunk("POST /tasks", "POST /tasks: create a task", &[]);
+        let code = chunk("src/app.rs:1-90", "struct Task", &[
+            ("file", "src/app.rs".into()),
+            ("start_line", MetaValue::Int(10)),
+            ("end_line", MetaValue::Int(12)),
+        ]);
+        let chunks = vec![&post, &code];
+
+        let template = PromptTemplate::new("t", "{history}{sources}\n{chunks}Q: {question}");
+        let history = vec![("list tasks?".to_string(), "GET /tasks [1]".to_string())];
+        let prompt = template.render("create one?", &chunks, &history);
+        assert!(prompt.starts_with("Conversation so far:\nUser: list tasks?\nAssistant: GET /tasks [1]\n\n"));
+        assert!(prompt.contains("[1] POST /tasks\n[2] src/app.rs:10-12\n[1] POST /tasks: create a task"));
+        assert!(prompt.ends_with("Q: create one?"));
+
+        // placeholders in retrieved text or history are not expanded again
+        let quoted = chunk("README.md", "templates use {question} and {chunks}", &[]);
+        let history = vec![("what is {sources}?".to_string(), "a placeholder".to_string())];
+        let prompt = template.render("{x}", &[&quoted], &history);
+        assert!(prompt.contains("User: what is {sources}?\n"));
+        assert!(prompt.contains("[1] templates use {question} and {chunks}\n"));
+        assert!(prompt.ends_with("Q: {x}"));
+
+        assert_eq!(citations("use [2], see also [1, 2] and [7]", 2), vec![2, 1]);
+        assert_eq!(with_sources("call it [2]", &chunks), "call it [2]\n\nSources:\n[2] src/app.rs:10-12\n");
+        assert!(with_sources("no idea", &chunks).ends_with("[1] POST /tasks\n[2] src/app.rs:10-12\n"));
+    }
+
+    #[test]
+    fn test_load_prefers_files() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(PromptTemplate::load_from(dir.path(), "openapi").unwrap().text, BUILTIN[1].1);
+        assert!(PromptTemplate::load_from(dir.path(), "missing").is_err());
+
+        fs::write(dir.path().join("openapi.txt"), "{question}?").unwrap();
+        assert_eq!(PromptTemplate::load_from(dir.path(), "openapi").unwrap().text, "{question}?");
+    }
+
+    #[test]
+    fn test_load_rejects_paths() {
+        let dir = TempDir::new().unwrap();
+        let prompts = dir.path().join("prompts");
+        fs::create_dir(&prompts).unwrap();
+        fs::write(dir.path().join("secret.txt"), "{question}").unwrap();
+
+        let err = PromptTemplate::load_from(&prompts, "../secret").unwrap_err();
+        assert!(err.to_string().contains("invalid prompt template name"));
+        assert!(PromptTemplate::load_from(&prompts, "").is_err());
+        let err = PromptTemplate::load_from(&prompts, "code_v2-short").unwrap_err();
+        assert!(err.to_string().contains("no prompt template"));
+    }
+}
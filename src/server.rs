@@ -1,25 +1,106 @@
 use std::{
     io::{BufReader, prelude::*},
     net::{TcpListener, TcpStream},
-    error::Error
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, atomic::{AtomicBool, Ordering}},
+    thread,
+    time::Duration,
 };
 use std::collections::HashMap;
+use std::env;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use crate::app;
+use crate::models;
+use crate::logs;
+use crate::document::Filter;
 // TODO: use a temporal LRU
 
 pub const PORT: u16 = 7878;
+// registered agents are persisted here and restored on startup
+pub const INDEX_DIR: &str = "src/data/index";
+const MANIFEST_FILE: &str = "manifest.json";
+// characters of the key kept readable in index file names, taken from the end where urls differ
+const STEM_PREFIX_LEN: usize = 32;
+
+// one entry per persisted agent in INDEX_DIR/manifest.json
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AgentRecord {
+    key: String,
+    kind: String,
+    source: String,
+    file: String,
+    // the agent's effective config, overrides included; absent in older manifests
+    #[serde(default)]
+    config: Option<app::AgentConfig>,
+}
+
 pub struct API{
     agents: HashMap<String,  Box<dyn app::AgentI>>,
     curr_agent: String, // the agent the user is currently querying
+    records: Vec<AgentRecord>,
+    index_dir: PathBuf,
 }
 
 impl API {
     pub fn new() -> Result<Self, Box<dyn Error>> {
         let agents = HashMap::new();
-        Ok(Self {
+        let mut api = Self {
             agents,
-            curr_agent: String::new()
-        })
+            curr_agent: String::new(),
+            records: vec![],
+            index_dir: PathBuf::from(INDEX_DIR),
+        };
+        api.restore_agents()?;
+        Ok(api)
+    }
+
+    // loads every agent listed in the manifest from its saved index
+    fn restore_agents(&mut self) -> Result<(), Box<dyn Error>> {
+        let manifest = self.index_dir.join(MANIFEST_FILE);
+        if !manifest.exists() {
+            return Ok(());
+        }
+        let records: Vec<AgentRecord> = serde_json::from_str(&fs::read_to_string(manifest)?)?;
+
+        for record in records {
+            let path = self.index_dir.join(&record.file);
+            let Some(specialization) = app::AgentSpecialization::parse(&record.kind) else {
+                println!("Skipping {}: unknown agent kind {}", record.key, record.kind);
+                continue;
+            };
+            let config = record.config.clone().unwrap_or_else(|| specialization.defaults());
+            match app::AgentBuilder::load(specialization, &path, &config) {
+                Ok(agent) => {
+                    println!("Restored {}", record.key);
+                    self.curr_agent = record.key.clone();
+                    self.agents.insert(record.key.clone(), agent);
+                    self.records.push(record);
+                }
+                Err(e) => println!("Failed to restore {}: {}", record.key, e),
+            }
+        }
+        Ok(())
+    }
+
+    // saves the agent's index and records it in the manifest
+    fn persist_agent(&mut self, key: &str, kind: &str, source: &str, config: Option<app::AgentConfig>) -> Result<(), Box<dyn Error>> {
+        let file = format!("{}.json", index_file_stem(key));
+        if let Some(agent) = self.agents.get(key) {
+            agent.save(&self.index_dir.join(&file))?;
+        }
+
+        self.records.retain(|r| r.key != key);
+        self.records.push(AgentRecord {
+            key: key.to_string(),
+            kind: kind.to_string(),
+            source: source.to_string(),
+            file,
+            config,
+        });
+        write_manifest(&self.index_dir, &self.records)
     }
     
     pub fn listen(&mut self) {
@@ -59,17 +140,33 @@ impl API {
         };
         // need an actual parser e.g., add api
         let ans = match words[0] {
+            "add" => self.add_agent(&words[1..]),
             "apiadd" => {
-                self.init_openapiagent(words[1])
+                self.add_agent_of(&words[1..], app::AgentSpecialization::OpenAPI)
+            },
+            "codeadd" => {
+                self.add_agent_of(&words[1..], app::AgentSpecialization::Codebase)
             },
             "ask" => {
                 let q = &words[1..].join(" ");
                 self.ask_agent(q)
             }
             "set" => self.set_agent(&words[1..].join(" ")),
+            "refresh" => self.refresh_agent(),
+            "filter" => self.set_filters(&words[1..].join(" ")),
+            "mode" => self.set_retrieval_mode(&words[1..].join(" ")),
+            "rerank" => self.set_reranker(&words[1..]),
+            "qa" => self.set_qa(&words[1..]),
+            "synthesize" => self.set_llm(&words[1..]),
+            "prompt" => self.set_prompt(&words[1..].join(" ")),
+            "diversify" => self.set_diversity(&words[1..]),
+            "window" => self.set_window(&words[1..].join(" ")),
+            "retention" => self.set_retention(&words[1..]),
             "ls" => self.list_agents(),
             _ => Ok("Unknown input".to_string())
-        }.unwrap();
+        };
+        // a failing command is reported to the client, it must not take the server down
+        let ans = ans.unwrap_or_else(|e| format!("Failed: {}", e));
 
         let msg = format!("{}\n", ans);
         let size = msg.len() as u32;
@@ -96,26 +193,345 @@ impl API {
         Ok(res)
     }
 
-    fn init_openapiagent(&mut self, url: &str) -> Result<String, Box<dyn Error>> {
-        // let test_key = "/Users/brianbarry/Desktop/ucsd-its/mini_rag/src/data/openapi_eda.json";
-        // fpath = test_key;
-        let agent = app::OpenAPIAgent::new(url).unwrap();
+    // "add <url|file|dir|glob> [kind=.. chunk_size=.. overlap=.. model=.. mode=..]"
+    fn add_agent(&mut self, args: &[&str]) -> Result<String, Box<dyn Error>> {
+        let usage = "Usage: add <url|file|dir|glob> [kind=<openapi|codebase|docs|educational|debugger> chunk_size=<n> overlap=<n> model=<key> mode=<mode> index=<brute|hnsw[:m:ef_construction:ef_search]>]";
+        let Some((source, settings)) = args.split_first() else {
+            return Ok(usage.to_string());
+        };
+        let mut builder = app::AgentBuilder::new(source);
+        for setting in settings {
+            let Some((key, value)) = setting.split_once('=') else {
+                return Ok(usage.to_string());
+            };
+            builder = match builder.set(key, value) {
+                Ok(builder) => builder,
+                Err(e) => return Ok(e),
+            };
+        }
+        self.init_agent(builder)
+    }
+
+    // apiadd and codeadd, add with the kind already given
+    fn add_agent_of(&mut self, args: &[&str], specialization: app::AgentSpecialization) -> Result<String, Box<dyn Error>> {
+        let kind = format!("kind={}", specialization.key());
+        let mut args = args.to_vec();
+        args.push(&kind);
+        self.add_agent(&args)
+    }
 
-        let key = format!("OpenAPI agent @ {}", url);
+    fn init_agent(&mut self, builder: app::AgentBuilder) -> Result<String, Box<dyn Error>> {
+        let specialization = builder.kind()?;
+        let source = builder.source().to_string();
+        let key = format!("{} agent @ {}", specialization.label(), source);
         if self.agents.contains_key(&key) {
             return Ok("Key already exists".to_string())
         }
-        self.agents.insert(key.to_string(), Box::new(agent));
+        let config = builder.config(specialization);
+        let agent = with_progress(&key, || builder.specialization(specialization).build())?;
+        self.agents.insert(key.to_string(), agent);
         self.curr_agent = key.to_string(); // TODO: use ref instead
-        Ok("Success".to_string())
+        self.persist_agent(&key, specialization.key(), &source, Some(config))
+            .map_err(|e| format!("added {}, but its index was not saved: {}", key, e))?;
+        Ok(format!("Success, added {}", key))
+    }
+
+    // "filter method == POST and path startswith /tasks", "filter clear" to reset
+    fn set_filters(&mut self, expr: &str) -> Result<String, Box<dyn Error>> {
+        let Some(agent) = self.agents.get_mut(&self.curr_agent) else {
+            return Ok("No agent selected".to_string());
+        };
+        if expr.is_empty() || expr == "clear" {
+            agent.options_mut().filters.clear();
+            return Ok("Filters cleared".to_string());
+        }
+        let filters = match expr.split(" and ").map(Filter::parse).collect::<Result<Vec<_>, _>>() {
+            Ok(filters) => filters,
+            Err(e) => return Ok(format!("Invalid filter: {}", e)),
+        };
+        let res = filters.iter().map(|f| f.to_string()).collect::<Vec<_>>().join(" and ");
+        agent.options_mut().filters = filters;
+        Ok(format!("Filtering on {}", res))
+    }
+
+    fn set_retrieval_mode(&mut self, spec: &str) -> Result<String, Box<dyn Error>> {
+        let Some(agent) = self.agents.get_mut(&self.curr_agent) else {
+            return Ok("No agent selected".to_string());
+        };
+        match app::RetrievalMode::parse(spec) {
+            Ok(mode) => {
+                let res = format!("Retrieval mode set to {:?}", mode);
+                agent.options_mut().mode = mode;
+                Ok(res)
+            }
+            Err(e) => Ok(e),
+        }
+    }
+
+    // "rerank off", "rerank" or "rerank <candidates> <top_n>"
+    fn set_reranker(&mut self, args: &[&str]) -> Result<String, Box<dyn Error>> {
+        let Some(agent) = self.agents.get_mut(&self.curr_agent) else {
+            return Ok("No agent selected".to_string());
+        };
+        if args == ["off"] {
+            agent.set_reranker(None)?;
+            return Ok("Reranking off".to_string());
+        }
+        let usage = "Usage: rerank [off | <candidates> <top_n>]".to_string();
+        let config = match args {
+            [] => app::RerankConfig::default(),
+            [candidates, top_n] => match (candidates.parse(), top_n.parse()) {
+                (Ok(candidates), Ok(top_n)) => app::RerankConfig { candidates, top_n },
+                _ => return Ok(usage),
+            },
+            _ => return Ok(usage),
+        };
+        agent.set_reranker(Some(config))?;
+        Ok(format!("Reranking top {} candidates down to {}", config.candidates, config.top_n))
+    }
+
+    // "qa", "qa <n_best> <min_confidence>", "qa off"
+    fn set_qa(&mut self, args: &[&str]) -> Result<String, Box<dyn Error>> {
+        let Some(agent) = self.agents.get_mut(&self.curr_agent) else {
+            return Ok("No agent selected".to_string());
+        };
+        if args == ["off"] {
+            agent.set_qa(None);
+            return Ok("QA off".to_string());
+        }
+        let usage = "Usage: qa [off | <n_best> <min_confidence>]".to_string();
+        let config = match args {
+            [] => app::QaConfig::default(),
+            [n_best, min_confidence] => match (n_best.parse(), min_confidence.parse()) {
+                (Ok(n_best), Ok(min_confidence)) => app::QaConfig { n_best, min_confidence, ..Default::default() },
+                _ => return Ok(usage),
+            },
+            _ => return Ok(usage),
+        };
+        agent.set_qa(Some(config));
+        Ok(format!("Answering with the {} best spans above {:.0}% confidence", config.n_best, config.min_confidence * 100.))
+    }
+
+    // "synthesize" uses the MINI_RAG_LLM_* endpoint, "synthesize <url> [model]",
+    // "synthesize local" the onnx decoder, "synthesize mock", "synthesize off"
+    fn set_llm(&mut self, args: &[&str]) -> Result<String, Box<dyn Error>> {
+        let Some(agent) = self.agents.get_mut(&self.curr_agent) else {
+            return Ok("No agent selected".to_string());
+        };
+        let backend: Box<dyn app::LlmBackend> = match args {
+            ["off"] => {
+                agent.set_llm(None);
+                return Ok("Synthesis off".to_string());
+            }
+            ["mock"] => Box::new(app::MockBackend::default()),
+            ["local"] => {
+                let config = models::GenerateConfig { stop: vec!["\nQuestion:".to_string()], ..Default::default() };
+                Box::new(app::LocalBackend::new(config)?)
+            }
+            [] => Box::new(app::OpenAiBackend::from_env()),
+            [url] => Box::new(app::OpenAiBackend { base_url: url.trim_end_matches('/').to_string(), ..app::OpenAiBackend::from_env() }),
+            [url, model] => Box::new(app::OpenAiBackend { api_key: env::var("MINI_RAG_LLM_API_KEY").ok(), ..app::OpenAiBackend::new(url, model) }),
+            _ => return Ok("Usage: synthesize [off | mock | local | <url> [model]]".to_string()),
+        };
+        agent.set_llm(Some(backend));
+        Ok("Synthesizing answers from retrieved chunks".to_string())
+    }
+
+    // "prompt <name>" loads <prompt dir>/<name>.txt or a built-in template
+    fn set_prompt(&mut self, name: &str) -> Result<String, Box<dyn Error>> {
+        let Some(agent) = self.agents.get_mut(&self.curr_agent) else {
+            return Ok("No agent selected".to_string());
+        };
+        if name.is_empty() {
+            return Ok("Usage: prompt <name>".to_string());
+        }
+        agent.set_prompt(name)?;
+        Ok(format!("Using prompt {}", name))
+    }
+
+    // "diversify mmr [lambda]", "diversify collapse", "diversify off"
+    fn set_diversity(&mut self, args: &[&str]) -> Result<String, Box<dyn Error>> {
+        let Some(agent) = self.agents.get_mut(&self.curr_agent) else {
+            return Ok("No agent selected".to_string());
+        };
+        let options = agent.options_mut();
+        match args {
+            ["off"] => {
+                options.mmr = None;
+                options.collapse_overlaps = false;
+                Ok("Diversification off".to_string())
+            }
+            ["collapse"] => {
+                options.collapse_overlaps = true;
+                Ok("Collapsing overlapping chunks".to_string())
+            }
+            ["mmr"] => {
+                options.mmr = Some(app::MmrConfig::default());
+                Ok(format!("MMR with lambda {}", app::MmrConfig::default().lambda))
+            }
+            ["mmr", lambda] => match lambda.parse::<f32>() {
+                Ok(lambda) if (0.0..=1.0).contains(&lambda) => {
+                    options.mmr = Some(app::MmrConfig { lambda, ..Default::default() });
+                    Ok(format!("MMR with lambda {}", lambda))
+                }
+                _ => Ok("lambda must be between 0 and 1".to_string()),
+            },
+            _ => Ok("Usage: diversify [mmr [lambda] | collapse | off]".to_string()),
+        }
+    }
+
+    // "window last 30m", "window around 14:00", "window 13:50 14:10", "window off"
+    fn set_window(&mut self, spec: &str) -> Result<String, Box<dyn Error>> {
+        let Some(agent) = self.agents.get_mut(&self.curr_agent) else {
+            return Ok("No agent selected".to_string());
+        };
+        if spec.is_empty() {
+            return Ok("Usage: window [last <duration> | around <time> | <from> <to> | off]".to_string());
+        }
+        Ok(agent.set_window(spec)?)
+    }
+
+    // "retention <max_records> [<max_age>]", "-" for no limit, "retention off" keeps everything
+    fn set_retention(&mut self, args: &[&str]) -> Result<String, Box<dyn Error>> {
+        let Some(agent) = self.agents.get_mut(&self.curr_agent) else {
+            return Ok("No agent selected".to_string());
+        };
+        let usage = "Usage: retention [off | <max_records|-> [<max_age, e.g. 12h>]]".to_string();
+        let max_records = match args.first() {
+            Some(&"off") if args.len() == 1 => None,
+            Some(&"-") => None,
+            Some(n) => match n.parse() {
+                Ok(n) => Some(n),
+                Err(_) => return Ok(usage),
+            },
+            None => return Ok(usage),
+        };
+        let max_age = match args.get(1) {
+            None => None,
+            Some(age) => match logs::parse_duration(age) {
+                Some(age) => Some(age),
+                None => return Ok(usage),
+            },
+        };
+        if args.len() > 2 {
+            return Ok(usage);
+        }
+        let retention = logs::Retention { max_records, max_age };
+        let res = agent.set_retention(retention)?;
+
+        let key = self.curr_agent.clone();
+        if let Some(record) = self.records.iter().find(|r| r.key == key).cloned() {
+            self.persist_agent(&key, &record.kind, &record.source, record.config)
+                .map_err(|e| format!("{}, but the index was not saved: {}", res, e))?;
+        }
+        Ok(res)
+    }
+
+    fn refresh_agent(&mut self) -> Result<String, Box<dyn Error>> {
+        let Some(agent) = self.agents.get_mut(&self.curr_agent) else {
+            return Ok("No agent selected".to_string());
+        };
+        let key = self.curr_agent.clone();
+        let res = with_progress(&key, || agent.refresh())?;
+
+        if let Some(record) = self.records.iter().find(|r| r.key == key).cloned() {
+            self.persist_agent(&key, &record.kind, &record.source, record.config)
+                .map_err(|e| format!("{}, but the index was not saved: {}", res, e))?;
+        }
+        Ok(res)
     }
 
     fn ask_agent(&mut self, query: &str) -> Result<String, Box<dyn Error>> {
         // let test_key = "/Users/brianbarry/Desktop/ucsd-its/mini_rag/src/data/openapi_eda.json";
 
-        let ag: & mut Box<dyn app::AgentI> = self.agents.get_mut(&self.curr_agent).unwrap();
-        let res = ag.execute(query)?;
-        Ok(res)
+        let Some(ag) = self.agents.get_mut(&self.curr_agent) else {
+            return Ok("No agent selected".to_string());
+        };
+        Ok(ag.execute(query)?)
+    }
+}
+
+// prints embedding progress to the server console while `index` runs
+fn with_progress<T>(label: &str, index: impl FnOnce() -> T) -> T {
+    let finished = Arc::new(AtomicBool::new(false));
+    let reporter = {
+        let finished = finished.clone();
+        let label = label.to_string();
+        thread::spawn(move || {
+            let mut last = (0, 0);
+            while !finished.load(Ordering::Relaxed) {
+                let progress = models::EMBED_PROGRESS.get();
+                if progress != last && progress.1 > 0 {
+                    println!("Indexing {}: {}/{} chunks", label, progress.0, progress.1);
+                    last = progress;
+                }
+                thread::sleep(Duration::from_millis(500));
+            }
+        })
+    };
+    let res = index();
+    finished.store(true, Ordering::Relaxed);
+    let _ = reporter.join();
+    res
+}
+
+fn write_manifest(index_dir: &Path, records: &[AgentRecord]) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(index_dir)?;
+    fs::write(index_dir.join(MANIFEST_FILE), serde_json::to_string_pretty(records)?)?;
+    Ok(())
+}
+
+// agent keys contain urls: a short readable part of file name safe characters,
+// then a hash of the whole key so that keys sanitizing alike don't share a file
+fn index_file_stem(key: &str) -> String {
+    let skip = key.chars().count().saturating_sub(STEM_PREFIX_LEN);
+    let prefix: String = key.chars()
+        .skip(skip)
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let digest: String = Sha256::digest(key.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}_{}", prefix, &digest[..16])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_file_stem() {
+        let a = index_file_stem("https://example.com/specs/api-v1.json");
+        let b = index_file_stem("https://example.com/specs/api_v1.json");
+        assert_ne!(a, b);
+        assert!(a.contains("specs_api_v1_json_"));
+        assert!(a.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+        assert_eq!(a, index_file_stem("https://example.com/specs/api-v1.json"));
 
+        let long = index_file_stem(&"x".repeat(1000));
+        assert!(long.len() <= STEM_PREFIX_LEN + 17);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_manifest_keeps_config() {
+        let mut config = app::AgentSpecialization::Docs.defaults();
+        config.chunk_size = 123;
+        config.mode = app::RetrievalMode::parse("hybrid weighted 0.7").unwrap();
+        let record = AgentRecord {
+            key: "docs agent @ notes/*.md".to_string(),
+            kind: "docs".to_string(),
+            source: "notes/*.md".to_string(),
+            file: "notes.json".to_string(),
+            config: Some(config.clone()),
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        write_manifest(dir.path(), &[record]).unwrap();
+        let records: Vec<AgentRecord> =
+            serde_json::from_str(&fs::read_to_string(dir.path().join(MANIFEST_FILE)).unwrap()).unwrap();
+        assert_eq!(records[0].config, Some(config));
+
+        // manifests written before configs were stored still load
+        let old = r#"[{"key": "k", "kind": "docs", "source": "s", "file": "f.json"}]"#;
+        let records: Vec<AgentRecord> = serde_json::from_str(old).unwrap();
+        assert_eq!(records[0].config, None);
+    }
+}
@@ -1,5 +1,9 @@
 use text_splitter::{TextSplitter, ChunkConfig};
 use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::grep::SKIPPED_DIRS;
 
 pub fn chunk_text(text: &str, chunk_size: usize, overlap: usize) -> Result<Vec<String>, Box<dyn Error>> {
     // TODO return iterator instead for speed
@@ -7,4 +11,111 @@ pub fn chunk_text(text: &str, chunk_size: usize, overlap: usize) -> Result<Vec<S
     let splitter = TextSplitter::new(conf);
     let chunks = splitter.chunks(text).map(|s| s.to_string()).collect();
     Ok(chunks)
-}
\ No newline at end of file
+}
+
+// like chunk_text, but keeps the byte offset of each chunk within `text`
+pub fn chunk_text_with_offsets(text: &str, chunk_size: usize, overlap: usize) -> Result<Vec<(usize, String)>, Box<dyn Error>> {
+    let conf = ChunkConfig::new(chunk_size).with_overlap(overlap)?;
+    let splitter = TextSplitter::new(conf);
+    let chunks = splitter.chunk_indices(text).map(|(i, s)| (i, s.to_string())).collect();
+    Ok(chunks)
+}
+
+// every file under dir, skipping hidden entries and build/dependency directories, sorted
+pub fn walk_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if name.starts_with('.') {
+                continue;
+            }
+            if path.is_dir() {
+                if !SKIPPED_DIRS.contains(&name) {
+                    pending.push(path);
+                }
+            } else if path.is_file() {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// http(s) and file:// locations, never globs even when they contain a '?'
+pub fn is_url(location: &str) -> bool {
+    ["http://", "https://", "file://"].iter().any(|scheme| location.starts_with(scheme))
+}
+
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+// files matching a pattern like "docs/**/*.md", relative patterns are matched from the
+// current directory. * and ? stay within a path component, ** spans any number of them
+pub fn glob(pattern: &str) -> io::Result<Vec<PathBuf>> {
+    // walk from the deepest directory without wildcards
+    let base: PathBuf = Path::new(pattern).components()
+        .take_while(|c| !is_glob(&c.as_os_str().to_string_lossy()))
+        .collect();
+    let base = if base.as_os_str().is_empty() { PathBuf::from(".") } else { base };
+    let pattern = pattern.strip_prefix("./").unwrap_or(pattern);
+
+    let files = walk_files(&base)?.into_iter()
+        .filter(|f| {
+            let path = f.to_string_lossy();
+            glob_match(pattern, path.strip_prefix("./").unwrap_or(&path))
+        })
+        .collect();
+    Ok(files)
+}
+
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    glob_match_from(&pattern, &path)
+}
+
+fn glob_match_from(p: &[char], s: &[char]) -> bool {
+    match p {
+        [] => s.is_empty(),
+        ['*', '*', '/', rest @ ..] => {
+            // zero or more whole directories
+            (0..=s.len()).any(|i| (i == 0 || s[i - 1] == '/') && glob_match_from(rest, &s[i..]))
+        }
+        ['*', '*', rest @ ..] => (0..=s.len()).any(|i| glob_match_from(rest, &s[i..])),
+        ['*', rest @ ..] => {
+            for i in 0..=s.len() {
+                if glob_match_from(rest, &s[i..]) {
+                    return true;
+                }
+                if i < s.len() && s[i] == '/' {
+                    break;
+                }
+            }
+            false
+        }
+        ['?', rest @ ..] => !s.is_empty() && s[0] != '/' && glob_match_from(rest, &s[1..]),
+        [c, rest @ ..] => s.first() == Some(c) && glob_match_from(rest, &s[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("docs/*.md", "docs/intro.md"));
+        assert!(!glob_match("docs/*.md", "docs/guide/intro.md"));
+        assert!(glob_match("docs/**/*.md", "docs/intro.md"));
+        assert!(glob_match("docs/**/*.md", "docs/guide/deep/intro.md"));
+        assert!(!glob_match("docs/**/*.md", "docsx/intro.md"));
+        assert!(!glob_match("a/**/b", "a/xb"));
+        assert!(glob_match("src/?pp.rs", "src/app.rs"));
+        assert!(!glob_match("src/?pp.rs", "src/ap.rs"));
+    }
+}